@@ -0,0 +1,484 @@
+//! RFC 7616 Digest 鉴权
+//!
+//! nonce 在 407 响应中下发并保存在内存中，超过 nonce_timeout 之后客户端需要使用新的 nonce，
+//! 同一个 nonce 的 nc 必须递增，重复使用的 nc 被当作重放拒绝
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::error;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use super::UserStore;
+use crate::config;
+use crate::utils;
+
+// 最多保存的 nonce 数量，超过时删除最早下发的
+const MAX_NONCES: usize = 65536;
+
+/// 下发给客户端的 nonce，所有连接共享，重新加载配置时保留
+#[derive(Default)]
+pub struct NonceStore {
+    nonces: Mutex<Nonces>,
+}
+
+#[derive(Default)]
+struct Nonces {
+    nonces: HashMap<String, Nonce>,
+    // 按照下发的顺序保存，过期和超过数量时从最早下发的开始删除，
+    // 其中的 nonce 可能已经因为过期从 nonces 中删除
+    order: VecDeque<(Instant, String)>,
+}
+
+struct Nonce {
+    created: Instant,
+    // 最近一次使用的 nc，0 表示还没有使用
+    nc: u32,
+}
+
+/// digest 鉴权失败的原因
+#[derive(Debug, PartialEq, Eq)]
+pub enum DigestError {
+    /// 参数缺失或者格式有误
+    Malformed,
+    /// 用户不存在、被禁用或者 response 不正确
+    Invalid,
+    /// nonce 过期或者不存在（例如重启之后），response 正确，客户端可以使用新的 nonce 重试
+    Stale,
+    /// nc 没有递增，可能是重放
+    Replayed,
+}
+
+impl NonceStore {
+    /// 生成新的 nonce，同时清理过期的 nonce
+    pub fn issue(&self, timeout: Duration) -> String {
+        let nonce = random_hex();
+        let mut guard = self.lock();
+        // 在锁中获取时间，保证 order 中的时间是递增的
+        let now = Instant::now();
+        let Nonces { nonces, order } = &mut *guard;
+        while let Some((created, oldest)) = order.front() {
+            if now.duration_since(*created) < timeout && nonces.len() < MAX_NONCES {
+                break;
+            }
+            nonces.remove(oldest);
+            order.pop_front();
+        }
+        nonces.insert(
+            nonce.clone(),
+            Nonce {
+                created: now,
+                nc: 0,
+            },
+        );
+        order.push_back((now, nonce.clone()));
+        nonce
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Nonces> {
+        match self.nonces.lock() {
+            Ok(nonces) => nonces,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    // 检查 nonce 是否有效以及 nc 是否递增，有效时记录 nc
+    fn check(&self, nonce: &str, nc: u32, timeout: Duration) -> Result<(), DigestError> {
+        let mut guard = self.lock();
+        let nonces = &mut guard.nonces;
+        let entry = match nonces.get_mut(nonce) {
+            Some(entry) => entry,
+            None => return Err(DigestError::Stale),
+        };
+        if entry.created.elapsed() >= timeout {
+            nonces.remove(nonce);
+            return Err(DigestError::Stale);
+        }
+        if nc <= entry.nc {
+            return Err(DigestError::Replayed);
+        }
+        entry.nc = nc;
+        Ok(())
+    }
+}
+
+/// `Proxy-Authorization: Digest` 中的参数
+#[derive(Debug, Default, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub realm: String,
+    pub nonce: String,
+    pub uri: String,
+    pub response: String,
+    pub algorithm: String,
+    pub qop: String,
+    pub nc: String,
+    pub cnonce: String,
+}
+
+impl Credentials {
+    /// 解析 `Digest` 之后的参数，缺少必需的参数或者格式有误时返回 None
+    ///
+    /// 不支持 `userhash=true`
+    pub fn parse(params: &str) -> Option<Credentials> {
+        let mut credentials = Credentials::default();
+        for (key, value) in parse_params(params)? {
+            let field = match key.to_ascii_lowercase().as_str() {
+                "username" => &mut credentials.username,
+                "realm" => &mut credentials.realm,
+                "nonce" => &mut credentials.nonce,
+                "uri" => &mut credentials.uri,
+                "response" => &mut credentials.response,
+                "algorithm" => &mut credentials.algorithm,
+                "qop" => &mut credentials.qop,
+                "nc" => &mut credentials.nc,
+                "cnonce" => &mut credentials.cnonce,
+                "userhash" if value.eq_ignore_ascii_case("true") => return None,
+                _ => continue,
+            };
+            *field = value;
+        }
+        let required = [
+            &credentials.username,
+            &credentials.nonce,
+            &credentials.uri,
+            &credentials.response,
+            &credentials.qop,
+            &credentials.nc,
+            &credentials.cnonce,
+        ];
+        if required.iter().any(|v| v.is_empty()) {
+            return None;
+        }
+        Some(credentials)
+    }
+
+    /// 计算期望的 response，algorithm 不支持时返回 None
+    pub fn expected_response(&self, method: &str, password: &str) -> Option<String> {
+        let hash: fn(&str) -> String = match self.algorithm.to_ascii_uppercase().as_str() {
+            "" | "MD5" => |s| hex(&Md5::digest(s)),
+            "SHA-256" => |s| hex(&Sha256::digest(s)),
+            _ => return None,
+        };
+        let ha1 = hash(&format!("{}:{}:{}", self.username, self.realm, password));
+        let ha2 = hash(&format!("{}:{}", method, self.uri));
+        Some(hash(&format!(
+            "{}:{}:{}:{}:{}:{}",
+            ha1, self.nonce, self.nc, self.cnonce, self.qop, ha2
+        )))
+    }
+}
+
+/// 验证 `Proxy-Authorization: Digest` 中的参数
+///
+/// uri 为请求行中的地址，只支持 `qop=auth`
+pub fn authenticate(
+    nonces: &NonceStore,
+    auth: &config::Auth,
+    users: &UserStore,
+    credentials: &Credentials,
+    method: &str,
+    uri: &str,
+) -> Result<(), DigestError> {
+    if credentials.realm != auth.realm
+        || !same_uri(uri, &credentials.uri)
+        || credentials.qop != "auth"
+    {
+        return Err(DigestError::Malformed);
+    }
+    let nc = u32::from_str_radix(&credentials.nc, 16).map_err(|_| DigestError::Malformed)?;
+
+    let password = users
+        .plain_password(&credentials.username)
+        .ok_or(DigestError::Invalid)?;
+    let expected = credentials
+        .expected_response(method, password)
+        .ok_or(DigestError::Malformed)?;
+    let response = credentials.response.to_ascii_lowercase();
+    if !bool::from(expected.as_bytes().ct_eq(response.as_bytes())) {
+        return Err(DigestError::Invalid);
+    }
+
+    // response 正确之后再检查 nonce，避免伪造的请求消耗 nc
+    nonces.check(
+        &credentials.nonce,
+        nc,
+        Duration::from_secs(auth.nonce_timeout),
+    )
+}
+
+/// 407 响应中的 `Proxy-Authenticate`，同时提供 SHA-256 和 MD5，stale 表示 nonce 过期
+pub fn challenges(auth: &config::Auth, nonces: &NonceStore, stale: bool) -> Vec<String> {
+    let nonce = nonces.issue(Duration::from_secs(auth.nonce_timeout));
+    let stale = if stale { ", stale=true" } else { "" };
+    ["SHA-256", "MD5"]
+        .iter()
+        .map(|algorithm| {
+            format!(
+                "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\"{}",
+                auth.realm, algorithm, nonce, stale
+            )
+        })
+        .collect()
+}
+
+// 请求行中的地址与 digest 的 uri 是否相同，请求行为绝对地址时 curl 等客户端只使用路径部分
+fn same_uri(target: &str, uri: &str) -> bool {
+    if target == uri {
+        return true;
+    }
+    match target.split_once("://") {
+        Some((_, rest)) => match rest.find('/') {
+            Some(i) => &rest[i..] == uri,
+            None => uri == "/",
+        },
+        None => false,
+    }
+}
+
+// 解析逗号分隔的 `key=value` 以及 `key="quoted value"`
+fn parse_params(params: &str) -> Option<Vec<(String, String)>> {
+    let mut result = Vec::new();
+    let mut chars = params.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| *c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return Some(result);
+        }
+
+        let mut key = String::new();
+        for c in chars.by_ref() {
+            if c == '=' {
+                break;
+            }
+            key.push(c);
+        }
+        let key = key.trim().to_string();
+        if key.is_empty() {
+            return None;
+        }
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            let mut closed = false;
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.push(chars.next()?),
+                    '"' => {
+                        closed = true;
+                        break;
+                    }
+                    c => value.push(c),
+                }
+            }
+            if !closed {
+                return None;
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if *c == ',' {
+                    break;
+                }
+                value.push(*c);
+                chars.next();
+            }
+            value = value.trim().to_string();
+        }
+        result.push((key, value));
+    }
+}
+
+// 随机的 128 位，十六进制编码
+fn random_hex() -> String {
+    let mut bytes = [0u8; 16];
+    if let Err(e) = getrandom::getrandom(&mut bytes) {
+        // 系统的随机数不可用时，使用时间和请求 ID，仍然不会重复
+        error!("get random bytes failed: {}", e);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let seed = format!("{}-{}", nanos, utils::request_id());
+        bytes.copy_from_slice(&Sha256::digest(seed)[..16]);
+    }
+    hex(&bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn digest_test() {
+    // RFC 7616 3.9.1 中的例子
+    let params = r#"username="Mufasa", realm="http-auth@example.org",
+        uri="/dir/index.html", algorithm=MD5, nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v",
+        nc=00000001, cnonce="f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ", qop=auth,
+        response="8ca523f5e9506fed4657c9700eebdbec",
+        opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#;
+    let mut credentials = Credentials::parse(params).unwrap();
+    assert_eq!(credentials.username, "Mufasa");
+    assert_eq!(credentials.nc, "00000001");
+    assert_eq!(
+        credentials.expected_response("GET", "Circle of Life"),
+        Some("8ca523f5e9506fed4657c9700eebdbec".to_string())
+    );
+    credentials.algorithm = "SHA-256".to_string();
+    assert_eq!(
+        credentials.expected_response("GET", "Circle of Life"),
+        Some("753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1".to_string())
+    );
+    credentials.algorithm = "SHA-512-256".to_string();
+    assert_eq!(credentials.expected_response("GET", "Circle of Life"), None);
+
+    // 引号中的逗号以及转义
+    let params = parse_params(r#"a="x, \"y\"", b=2,,c="""#).unwrap();
+    assert_eq!(
+        params,
+        vec![
+            ("a".to_string(), "x, \"y\"".to_string()),
+            ("b".to_string(), "2".to_string()),
+            ("c".to_string(), "".to_string()),
+        ]
+    );
+    assert_eq!(parse_params(r#"a="x"#), None);
+    assert!(same_uri("http://example.com/a?b", "/a?b"));
+    assert!(same_uri("http://example.com", "/"));
+    assert!(!same_uri("http://example.com/a", "/b"));
+    assert!(!same_uri("example.com:443", "/"));
+    assert_eq!(Credentials::parse(r#"username="Mufasa""#), None);
+}
+
+#[test]
+fn authenticate_test() {
+    let auth = config::Auth {
+        enable: true,
+        scheme: config::Scheme::Digest,
+        realm: "rust-proxy".to_string(),
+        nonce_timeout: 60,
+        username: "rust".to_string(),
+        password: "proxy".to_string(),
+        ..config::Auth::default()
+    };
+    let users = UserStore::new(&auth).unwrap();
+    let nonces = NonceStore::default();
+
+    let header = &challenges(&auth, &nonces, false)[0];
+    assert!(header.starts_with("Digest realm=\"rust-proxy\", qop=\"auth\", algorithm=SHA-256"));
+    let nonce = header
+        .split("nonce=\"")
+        .nth(1)
+        .unwrap()
+        .trim_end_matches('"');
+
+    let params = |nc: &str, password: &str| {
+        let mut credentials = Credentials {
+            username: "rust".to_string(),
+            realm: "rust-proxy".to_string(),
+            nonce: nonce.to_string(),
+            uri: "example.com:443".to_string(),
+            algorithm: "SHA-256".to_string(),
+            qop: "auth".to_string(),
+            nc: nc.to_string(),
+            cnonce: "0a4f113b".to_string(),
+            ..Credentials::default()
+        };
+        credentials.response = credentials.expected_response("CONNECT", password).unwrap();
+        format!(
+            r#"username="{}", realm="{}", nonce="{}", uri="{}", algorithm={}, qop={}, nc={}, cnonce="{}", response="{}""#,
+            credentials.username,
+            credentials.realm,
+            credentials.nonce,
+            credentials.uri,
+            credentials.algorithm,
+            credentials.qop,
+            credentials.nc,
+            credentials.cnonce,
+            credentials.response
+        )
+    };
+    let check = |params: &str, uri: &str| {
+        let credentials = Credentials::parse(params).unwrap();
+        authenticate(&nonces, &auth, &users, &credentials, "CONNECT", uri)
+    };
+
+    assert_eq!(
+        check(&params("00000001", "proxy"), "example.com:443"),
+        Ok(())
+    );
+    assert_eq!(
+        check(&params("00000002", "proxy"), "example.com:443"),
+        Ok(())
+    );
+    // nc 没有递增
+    assert_eq!(
+        check(&params("00000002", "proxy"), "example.com:443"),
+        Err(DigestError::Replayed)
+    );
+    assert_eq!(
+        check(&params("00000003", "wrong"), "example.com:443"),
+        Err(DigestError::Invalid)
+    );
+    // uri 与请求行不同
+    assert_eq!(
+        check(&params("00000003", "proxy"), "other.com:443"),
+        Err(DigestError::Malformed)
+    );
+
+    // 过期的 nonce
+    let auth = config::Auth {
+        nonce_timeout: 0,
+        ..auth
+    };
+    assert_eq!(
+        authenticate(
+            &nonces,
+            &auth,
+            &users,
+            &Credentials::parse(&params("00000004", "proxy")).unwrap(),
+            "CONNECT",
+            "example.com:443"
+        ),
+        Err(DigestError::Stale)
+    );
+}
+
+#[test]
+fn nonce_store_test() {
+    let store = NonceStore::default();
+    let timeout = Duration::from_secs(60);
+    let first = store.issue(timeout);
+    let second = store.issue(timeout);
+    assert_ne!(first, second);
+    assert_eq!(store.check(&first, 1, timeout), Ok(()));
+
+    // 过期的 nonce 按照下发的顺序删除
+    let third = store.issue(Duration::ZERO);
+    {
+        let nonces = store.lock();
+        assert_eq!(nonces.nonces.len(), 1);
+        assert_eq!(nonces.order.len(), 1);
+    }
+    assert_eq!(store.check(&first, 2, timeout), Err(DigestError::Stale));
+    assert_eq!(store.check(&third, 1, timeout), Ok(()));
+
+    // 超过数量时删除最早下发的
+    for _ in 1..MAX_NONCES {
+        store.issue(timeout);
+    }
+    assert_eq!(store.lock().nonces.len(), MAX_NONCES);
+    store.issue(timeout);
+    let nonces = store.lock();
+    assert_eq!(nonces.nonces.len(), MAX_NONCES);
+    assert_eq!(nonces.order.len(), MAX_NONCES);
+    assert!(!nonces.nonces.contains_key(&third));
+}
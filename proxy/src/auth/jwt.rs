@@ -0,0 +1,278 @@
+//! JWT 鉴权
+//!
+//! 服务之间使用 `Proxy-Authorization: Bearer <token>` 访问代理，token 使用 HMAC 或者 RSA 签名，
+//! 验证签名以及 exp、iss、aud 之后，token 中的 claim 作为用户的身份用于过滤
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+
+use crate::config;
+use crate::filter::Identity;
+
+// HMAC 的密钥至少需要的字节数
+const MIN_SECRET_LEN: usize = 32;
+
+/// 根据 `server.auth.jwt` 验证 token
+pub struct JwtVerifier {
+    keys: Vec<Key>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: u64,
+    username_claim: String,
+    groups_claim: String,
+}
+
+struct Key {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+impl JwtVerifier {
+    /// 读取 RSA 公钥，密钥有误时返回错误
+    pub fn new(jwt: &config::Jwt) -> Result<JwtVerifier, String> {
+        if jwt.keys.is_empty() {
+            return Err("at least one key is required".to_string());
+        }
+        let mut keys = Vec::new();
+        for (i, key) in jwt.keys.iter().enumerate() {
+            keys.push(Key::new(key).map_err(|e| format!("keys[{}]: {}", i, e))?);
+        }
+        Ok(JwtVerifier {
+            keys,
+            issuer: jwt.issuer.clone(),
+            audience: jwt.audience.clone(),
+            leeway: jwt.leeway,
+            username_claim: jwt.username_claim.clone(),
+            groups_claim: jwt.groups_claim.clone(),
+        })
+    }
+
+    /// 验证 token，返回 token 对应的用户
+    ///
+    /// 只使用 algorithm 与 token 头部相同的密钥，配置了 kid 的密钥只验证 kid 相同的 token
+    pub fn verify(&self, token: &str) -> Result<Identity, String> {
+        let header = decode_header(token).map_err(|e| format!("invalid token: {}", e))?;
+        let mut result = Err(format!("no key for algorithm {:?}", header.alg));
+        let keys = self
+            .keys
+            .iter()
+            .filter(|k| k.algorithm == header.alg && (k.kid.is_none() || k.kid == header.kid));
+        for key in keys {
+            result = decode::<HashMap<String, Value>>(token, &key.key, &self.validation(key))
+                .map_err(|e| format!("invalid token: {}", e));
+            if result.is_ok() {
+                break;
+            }
+        }
+        self.identity(result?.claims)
+    }
+
+    fn validation(&self, key: &Key) -> Validation {
+        let mut validation = Validation::new(key.algorithm);
+        validation.leeway = self.leeway;
+        validation.validate_nbf = true;
+        let mut required = vec!["exp"];
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            required.push("iss");
+        }
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+            required.push("aud");
+        }
+        validation.set_required_spec_claims(&required);
+        validation
+    }
+
+    // username_claim 必须是非空的字符串
+    fn identity(&self, claims: HashMap<String, Value>) -> Result<Identity, String> {
+        let name = match claims.get(&self.username_claim) {
+            Some(Value::String(name)) if !name.is_empty() => name.clone(),
+            _ => return Err(format!("claim `{}` is missing", self.username_claim)),
+        };
+        let claims: HashMap<_, _> = claims
+            .into_iter()
+            .filter_map(|(k, v)| Some((k, claim_values(&v)?)))
+            .collect();
+        let groups = claims.get(&self.groups_claim).cloned().unwrap_or_default();
+        Ok(Identity {
+            name,
+            groups,
+            claims,
+        })
+    }
+}
+
+impl Key {
+    fn new(key: &config::JwtKey) -> Result<Key, String> {
+        let algorithm = Algorithm::from_str(&key.algorithm)
+            .map_err(|_| format!("unsupported algorithm `{}`", key.algorithm))?;
+        let decoding = match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secret = match (&key.secret, &key.public_key) {
+                    (Some(secret), None) => secret,
+                    _ => return Err(format!("{:?} requires secret only", algorithm)),
+                };
+                if secret.len() < MIN_SECRET_LEN {
+                    return Err(format!("secret must be at least {} bytes", MIN_SECRET_LEN));
+                }
+                DecodingKey::from_secret(secret.as_bytes())
+            }
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => {
+                let file = match (&key.secret, &key.public_key) {
+                    (None, Some(file)) => file,
+                    _ => return Err(format!("{:?} requires public_key only", algorithm)),
+                };
+                let pem = fs::read(file)
+                    .map_err(|e| format!("read public key {} failed: {}", file, e))?;
+                DecodingKey::from_rsa_pem(&pem)
+                    .map_err(|e| format!("invalid RSA public key {}: {}", file, e))?
+            }
+            _ => return Err(format!("unsupported algorithm `{}`", key.algorithm)),
+        };
+        Ok(Key {
+            kid: key.kid.clone(),
+            algorithm,
+            key: decoding,
+        })
+    }
+}
+
+// 不输出密钥
+impl fmt::Debug for JwtVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let keys: Vec<_> = self
+            .keys
+            .iter()
+            .map(|k| (k.kid.as_deref(), k.algorithm))
+            .collect();
+        f.debug_struct("JwtVerifier")
+            .field("keys", &keys)
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .finish()
+    }
+}
+
+// claim 的值转换成字符串，数组中的元素分别转换，对象和 null 不保存
+fn claim_values(value: &Value) -> Option<Vec<String>> {
+    let scalar = |value: &Value| match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    };
+    match value {
+        Value::Array(values) => Some(values.iter().filter_map(scalar).collect()),
+        value => scalar(value).map(|v| vec![v]),
+    }
+}
+
+#[test]
+fn jwt_test() {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    let secret = "0123456789abcdef0123456789abcdef";
+    let config = config::Jwt {
+        keys: vec![
+            config::JwtKey {
+                kid: None,
+                algorithm: "HS256".to_string(),
+                secret: Some(secret.to_string()),
+                public_key: None,
+            },
+            config::JwtKey {
+                kid: Some("2024".to_string()),
+                algorithm: "RS256".to_string(),
+                secret: None,
+                public_key: Some("test/jwt-public.pem".to_string()),
+            },
+        ],
+        issuer: Some("https://issuer.example.com".to_string()),
+        audience: Some("rust-proxy".to_string()),
+        leeway: 0,
+        username_claim: "sub".to_string(),
+        groups_claim: "groups".to_string(),
+    };
+    let verifier = JwtVerifier::new(&config).unwrap();
+
+    let now = jsonwebtoken::get_current_timestamp();
+    let claims = json!({
+        "sub": "ci",
+        "iss": "https://issuer.example.com",
+        "aud": "rust-proxy",
+        "exp": now + 60,
+        "groups": ["deploy", "build"],
+        "scope": "read",
+        "admin": false,
+        "meta": { "team": "infra" },
+    });
+    let hmac = EncodingKey::from_secret(secret.as_bytes());
+    let sign = |claims: &Value| encode(&Header::default(), claims, &hmac).unwrap();
+
+    let user = verifier.verify(&sign(&claims)).unwrap();
+    assert_eq!(user.name, "ci");
+    assert_eq!(user.groups, ["deploy", "build"]);
+    assert_eq!(user.claims["scope"], ["read"]);
+    assert_eq!(user.claims["admin"], ["false"]);
+    assert!(!user.claims.contains_key("meta"));
+
+    // RSA 签名，kid 必须相同
+    let rsa = EncodingKey::from_rsa_pem(include_bytes!("../../test/jwt-private.pem")).unwrap();
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some("2024".to_string());
+    let token = encode(&header, &claims, &rsa).unwrap();
+    assert_eq!(verifier.verify(&token).unwrap().name, "ci");
+    header.kid = Some("2023".to_string());
+    let token = encode(&header, &claims, &rsa).unwrap();
+    assert!(verifier.verify(&token).is_err());
+    // 使用 RSA 公钥作为 HMAC 密钥伪造的 token
+    let forged = EncodingKey::from_secret(include_bytes!("../../test/jwt-public.pem"));
+    let token = encode(&Header::default(), &claims, &forged).unwrap();
+    assert!(verifier.verify(&token).is_err());
+
+    let mut wrong = claims.clone();
+    wrong["exp"] = json!(now - 10);
+    assert!(verifier
+        .verify(&sign(&wrong))
+        .unwrap_err()
+        .contains("ExpiredSignature"));
+    let mut wrong = claims.clone();
+    wrong["iss"] = json!("https://other.example.com");
+    assert!(verifier
+        .verify(&sign(&wrong))
+        .unwrap_err()
+        .contains("InvalidIssuer"));
+    let mut wrong = claims.clone();
+    wrong.as_object_mut().unwrap().remove("aud");
+    assert!(verifier.verify(&sign(&wrong)).unwrap_err().contains("aud"));
+    let mut wrong = claims.clone();
+    wrong.as_object_mut().unwrap().remove("sub");
+    assert_eq!(
+        verifier.verify(&sign(&wrong)).unwrap_err(),
+        "claim `sub` is missing"
+    );
+    assert!(verifier.verify("not a token").is_err());
+
+    let mut bad = config.clone();
+    bad.keys[0].secret = Some("short".to_string());
+    assert!(JwtVerifier::new(&bad)
+        .unwrap_err()
+        .contains("keys[0]: secret must be at least 32 bytes"));
+    bad.keys[0].algorithm = "ES256".to_string();
+    assert!(JwtVerifier::new(&bad)
+        .unwrap_err()
+        .contains("unsupported algorithm `ES256`"));
+}
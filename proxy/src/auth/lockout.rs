@@ -0,0 +1,278 @@
+//! 鉴权失败的限制
+//!
+//! 分别记录每个客户端地址和用户名连续失败的次数，达到 max_failures 之后暂时锁定，
+//! 之后每次失败锁定的时间翻倍，锁定期间的请求直接拒绝，不再验证密码
+//!
+//! 只记录已有用户的失败次数，不存在的用户名不占用记录
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use super::UserStore;
+use crate::config::Lockout;
+
+// 每种记录最多保存的数量，超过时删除最早创建的记录
+const MAX_ENTRIES: usize = 65536;
+
+/// 所有连接共享的失败记录，重新加载配置时保留
+#[derive(Default)]
+pub struct FailureTracker {
+    clients: Tracker<IpAddr>,
+    users: Tracker<String>,
+}
+
+struct Tracker<K> {
+    entries: Mutex<Entries<K>>,
+}
+
+struct Entries<K> {
+    failures: HashMap<K, Failures>,
+    // 按照创建的顺序保存，超过数量时从最早创建的开始删除，
+    // 其中的记录可能已经因为鉴权成功从 failures 中删除
+    order: VecDeque<(Instant, K)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    created: Instant,
+    // 连续失败的次数
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl FailureTracker {
+    /// 客户端地址或者用户名被锁定时返回剩余的锁定时间
+    pub fn locked(&self, client: Option<IpAddr>, user: Option<&str>) -> Option<Duration> {
+        let now = Instant::now();
+        let client = client.and_then(|c| self.clients.locked(&c, now));
+        let user = user.and_then(|u| self.users.locked(u, now));
+        client.max(user)
+    }
+
+    /// 记录一次失败，达到限制时锁定，失败和锁定都记录到 security 日志中
+    ///
+    /// 用户名只有在 users 中存在时才记录失败次数
+    pub fn failure(
+        &self,
+        cfg: &Lockout,
+        users: &UserStore,
+        client: Option<IpAddr>,
+        user: Option<&str>,
+        reason: &str,
+    ) {
+        let now = Instant::now();
+        warn!(
+            target: "security",
+            "auth failure, client: {}, user: {}, reason: {}",
+            client.map_or("-".to_string(), |c| c.to_string()),
+            user.unwrap_or("-"),
+            reason
+        );
+        if cfg.max_failures == 0 {
+            return;
+        }
+        if let Some(client) = client {
+            self.clients.failure(cfg, client, "client", now);
+        }
+        if let Some(user) = user.filter(|u| users.contains(u)) {
+            self.users.failure(cfg, user.to_string(), "user", now);
+        }
+    }
+
+    /// 鉴权成功之后清除客户端地址和用户名的失败记录
+    pub fn success(&self, client: Option<IpAddr>, user: &str) {
+        if let Some(client) = client {
+            self.clients.remove(&client);
+        }
+        self.users.remove(user);
+    }
+}
+
+impl<K> Default for Tracker<K> {
+    fn default() -> Self {
+        Tracker {
+            entries: Mutex::new(Entries {
+                failures: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone + Display> Tracker<K> {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries<K>> {
+        match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    fn locked<Q>(&self, key: &Q, now: Instant) -> Option<Duration>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let until = self.lock().failures.get(key)?.locked_until?;
+        until.checked_duration_since(now).filter(|d| !d.is_zero())
+    }
+
+    // 返回新的锁定时间
+    fn failure(&self, cfg: &Lockout, key: K, kind: &str, now: Instant) -> Option<Duration> {
+        let reset_after = Duration::from_secs(cfg.reset_after);
+        let mut guard = self.lock();
+        let Entries { failures, order } = &mut *guard;
+        if !failures.contains_key(&key) {
+            // 每条记录在 order 中都有对应的项，order 的长度限制了记录的数量
+            while order.len() >= MAX_ENTRIES {
+                if let Some((created, oldest)) = order.pop_front() {
+                    if failures.get(&oldest).is_some_and(|f| f.created == created) {
+                        failures.remove(&oldest);
+                    }
+                }
+            }
+            order.push_back((now, key.clone()));
+        }
+
+        let failures = failures.entry(key.clone()).or_insert(Failures {
+            created: now,
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        if failures.expired(now, reset_after) {
+            failures.count = 0;
+            failures.locked_until = None;
+        }
+        failures.count = failures.count.saturating_add(1);
+        failures.last = now;
+        if failures.count < cfg.max_failures {
+            return None;
+        }
+
+        // 第 max_failures 次失败锁定 base_delay，之后每次翻倍
+        let exponent = (failures.count - cfg.max_failures).min(63);
+        let delay = cfg
+            .base_delay
+            .saturating_mul(1u64 << exponent)
+            .min(cfg.max_delay);
+        let delay = Duration::from_secs(delay);
+        failures.locked_until = Some(now + delay);
+        warn!(
+            target: "security",
+            "{} {} locked for {}s after {} consecutive auth failures",
+            kind,
+            key,
+            delay.as_secs(),
+            failures.count
+        );
+        Some(delay)
+    }
+
+    fn remove<Q>(&self, key: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.lock().failures.remove(key);
+    }
+}
+
+impl Failures {
+    // 没有锁定，并且超过 reset_after 没有失败
+    fn expired(&self, now: Instant, reset_after: Duration) -> bool {
+        let locked = self.locked_until.is_some_and(|until| until > now);
+        !locked && now.duration_since(self.last) >= reset_after
+    }
+}
+
+#[test]
+fn lockout_test() {
+    let cfg = Lockout {
+        max_failures: 3,
+        base_delay: 2,
+        max_delay: 10,
+        reset_after: 60,
+    };
+    let tracker: Tracker<String> = Tracker::default();
+    let start = Instant::now();
+    let at = |secs: u64| start + Duration::from_secs(secs);
+    let fail = |secs: u64| tracker.failure(&cfg, "alice".to_string(), "user", at(secs));
+
+    assert_eq!(fail(0), None);
+    assert_eq!(fail(0), None);
+    assert_eq!(tracker.locked("alice", at(0)), None);
+    // 第三次失败开始锁定，之后每次翻倍，最长 max_delay
+    assert_eq!(fail(0), Some(Duration::from_secs(2)));
+    assert_eq!(tracker.locked("alice", at(1)), Some(Duration::from_secs(1)));
+    assert_eq!(tracker.locked("alice", at(2)), None);
+    assert_eq!(tracker.locked("bob", at(1)), None);
+    assert_eq!(fail(2), Some(Duration::from_secs(4)));
+    assert_eq!(fail(6), Some(Duration::from_secs(8)));
+    assert_eq!(fail(14), Some(Duration::from_secs(10)));
+    assert_eq!(fail(24), Some(Duration::from_secs(10)));
+
+    // 超过 reset_after 没有失败之后重新计数
+    assert_eq!(fail(100), None);
+    assert_eq!(tracker.locked("alice", at(100)), None);
+
+    tracker.remove("alice");
+    assert!(tracker.lock().failures.is_empty());
+
+    // 超过数量时删除最早创建的记录
+    let tracker: Tracker<String> = Tracker::default();
+    for i in 0..=MAX_ENTRIES {
+        tracker.failure(&cfg, i.to_string(), "user", at(0));
+    }
+    let entries = tracker.lock();
+    assert_eq!(entries.failures.len(), MAX_ENTRIES);
+    assert!(!entries.failures.contains_key("0"));
+    assert!(entries.failures.contains_key(&MAX_ENTRIES.to_string()));
+    drop(entries);
+
+    // max_failures 为 0 时不限制
+    let auth = crate::config::Auth {
+        enable: true,
+        username: "alice".to_string(),
+        password: "secret".to_string(),
+        ..crate::config::Auth::default()
+    };
+    let users = UserStore::new(&auth).unwrap();
+    let tracker = FailureTracker::default();
+    let client = Some("10.0.0.1".parse().unwrap());
+    let disabled = Lockout {
+        max_failures: 0,
+        ..cfg.clone()
+    };
+    for _ in 0..10 {
+        tracker.failure(
+            &disabled,
+            &users,
+            client,
+            Some("alice"),
+            "invalid credentials",
+        );
+    }
+    assert_eq!(tracker.locked(client, Some("alice")), None);
+    for _ in 0..3 {
+        tracker.failure(&cfg, &users, client, Some("alice"), "invalid credentials");
+    }
+    // 不存在的用户名不记录
+    for _ in 0..3 {
+        tracker.failure(&cfg, &users, None, Some("bob"), "invalid credentials");
+    }
+    assert!(!tracker.users.lock().failures.contains_key("bob"));
+    assert!(tracker.locked(client, None).is_some());
+    assert!(tracker.locked(None, Some("alice")).is_some());
+    assert!(tracker
+        .locked(Some("10.0.0.2".parse().unwrap()), Some("bob"))
+        .is_none());
+    tracker.success(client, "alice");
+    assert_eq!(tracker.locked(client, Some("alice")), None);
+}
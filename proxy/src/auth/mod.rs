@@ -0,0 +1,66 @@
+//! 代理鉴权
+
+pub use digest::NonceStore;
+pub use jwt::JwtVerifier;
+pub use lockout::FailureTracker;
+pub use users::UserStore;
+
+pub mod digest;
+mod jwt;
+mod lockout;
+mod users;
+
+use crate::config::{Auth, Scheme};
+
+/// 407 响应中的 `Proxy-Authenticate`，stale 表示 digest 的 nonce 已经过期
+pub fn challenges(auth: &Auth, nonces: &NonceStore, stale: bool) -> Vec<String> {
+    let mut challenges = match auth.scheme {
+        Scheme::Basic => vec![format!("Basic realm=\"{}\"", auth.realm)],
+        Scheme::Digest => digest::challenges(auth, nonces, stale),
+    };
+    if auth.jwt.is_some() {
+        challenges.push(format!("Bearer realm=\"{}\"", auth.realm));
+    }
+    challenges
+}
+
+/// 解析 `Proxy-Authorization`，返回鉴权方式以及之后的凭据，格式有误时返回 None
+pub fn parse_authorization(header: &str) -> Option<(&str, &str)> {
+    let header = header.trim();
+    let (scheme, credentials) = header.split_once([' ', '\t'])?;
+    let credentials = credentials.trim();
+    // RFC 7230 中的 token
+    let token = |c: u8| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c);
+    if scheme.is_empty() || !scheme.bytes().all(token) || credentials.is_empty() {
+        return None;
+    }
+    Some((scheme, credentials))
+}
+
+#[test]
+fn parse_authorization_test() {
+    assert_eq!(
+        parse_authorization("Basic cnVzdDpwcm94eQ=="),
+        Some(("Basic", "cnVzdDpwcm94eQ=="))
+    );
+    assert_eq!(
+        parse_authorization(" Bearer \t abc.def "),
+        Some(("Bearer", "abc.def"))
+    );
+    assert_eq!(
+        parse_authorization("Digest username=\"rust\", realm=\"rust-proxy\""),
+        Some(("Digest", "username=\"rust\", realm=\"rust-proxy\""))
+    );
+    // 之前会在 a[6..] 处 panic 的头部
+    for header in [
+        "",
+        "Basic",
+        "Bas",
+        "Basic ",
+        "基本 abc",
+        "B@sic abc",
+        ":abc",
+    ] {
+        assert_eq!(parse_authorization(header), None, "{:?}", header);
+    }
+}
@@ -0,0 +1,365 @@
+//! 用户以及密码的验证
+//!
+//! 密码可以是明文，也可以是 htpasswd 格式的 bcrypt（`$2y$`）、argon2（`$argon2id$`）
+//! 以及 `{SHA}` 哈希，比较时使用固定时间的比较，避免通过响应时间猜测密码
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use sha1::{Digest, Sha1};
+use subtle::ConstantTimeEq;
+
+use crate::config;
+
+// 配置中的密码
+#[derive(Debug, PartialEq, Clone)]
+enum Password {
+    Plain(String),
+    Bcrypt(String),
+    Argon2(String),
+    // `{SHA}` 之后 base64 解码的 SHA-1
+    Sha1(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq)]
+struct User {
+    password: Password,
+    enable: bool,
+    // 用户所在的组
+    groups: Vec<String>,
+}
+
+/// 可以使用代理的用户
+///
+/// 由 `server.auth` 中的 username、users 以及 file 合并而成，用户名不能重复
+#[derive(Default)]
+pub struct UserStore {
+    users: HashMap<String, User>,
+    // 用户不存在时用来验证的哈希，与已有用户的计算时间相同，避免通过响应时间判断用户是否存在
+    dummy: Password,
+}
+
+impl UserStore {
+    /// 没有开启鉴权时返回空的 UserStore，不读取 htpasswd 文件
+    pub fn new(auth: &config::Auth) -> Result<UserStore, String> {
+        let mut store = UserStore::default();
+        if !auth.enable {
+            return Ok(store);
+        }
+
+        if !auth.username.is_empty() {
+            store.add(&auth.username, &auth.password, true)?;
+        }
+        for user in auth.users.iter() {
+            store.add(&user.name, &user.password, user.enable)?;
+        }
+        if let Some(file) = &auth.file {
+            let content = match fs::read_to_string(file) {
+                Ok(content) => content,
+                Err(e) => return Err(format!("read htpasswd file {} failed: {}", file, e)),
+            };
+            for (i, line) in content.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let result = match line.split_once(':') {
+                    Some((name, password)) => store.add(name, password, true),
+                    None => Err("expect `username:password`".to_string()),
+                };
+                if let Err(e) = result {
+                    return Err(format!("{} line {}: {}", file, i + 1, e));
+                }
+            }
+        }
+
+        for (i, group) in auth.groups.iter().enumerate() {
+            if group.name.is_empty() {
+                return Err("empty group name".to_string());
+            }
+            if auth.groups[..i].iter().any(|g| g.name == group.name) {
+                return Err(format!("duplicate group `{}`", group.name));
+            }
+            for name in group.users.iter() {
+                match store.users.get_mut(name) {
+                    Some(user) => user.groups.push(group.name.clone()),
+                    None => return Err(format!("group `{}`: unknown user `{}`", group.name, name)),
+                }
+            }
+        }
+
+        if auth.realm.contains('"') || auth.realm.contains('\\') {
+            return Err(format!("invalid realm `{}`", auth.realm));
+        }
+        let mut names: Vec<_> = store.users.keys().collect();
+        names.sort();
+        let slow = names
+            .iter()
+            .map(|name| &store.users[*name].password)
+            .find(|p| matches!(p, Password::Bcrypt(_) | Password::Argon2(_)));
+        if let Some(password) = slow {
+            store.dummy = password.clone();
+        }
+        // digest 需要使用明文的密码计算
+        if auth.scheme == config::Scheme::Digest {
+            for name in names {
+                if !matches!(store.users[name].password, Password::Plain(_)) {
+                    return Err(format!(
+                        "user `{}`: digest auth requires a plaintext password",
+                        name
+                    ));
+                }
+            }
+        }
+        Ok(store)
+    }
+
+    fn add(&mut self, name: &str, password: &str, enable: bool) -> Result<(), String> {
+        if name.is_empty() || name.contains(':') {
+            return Err(format!("invalid username `{}`", name));
+        }
+        if self.users.contains_key(name) {
+            return Err(format!("duplicate user `{}`", name));
+        }
+        let password = match Password::parse(password) {
+            Ok(password) => password,
+            Err(e) => return Err(format!("user `{}`: {}", name, e)),
+        };
+        self.users.insert(
+            name.to_string(),
+            User {
+                password,
+                enable,
+                groups: Vec::new(),
+            },
+        );
+        Ok(())
+    }
+
+    /// 验证用户名和密码，用户不存在、被禁用或者密码错误时返回 false
+    ///
+    /// 用户不存在或者被禁用时同样计算一次哈希，每次验证都重新计算，不缓存验证通过的密码
+    pub async fn verify(&self, username: &str, password: &str) -> bool {
+        let (expected, enable) = match self.users.get(username) {
+            Some(user) => (&user.password, user.enable),
+            None => (&self.dummy, false),
+        };
+        if let Password::Plain(_) | Password::Sha1(_) = expected {
+            return expected.matches(password) && enable;
+        }
+
+        // bcrypt 和 argon2 的计算比较慢，在专门的线程中计算，不阻塞其它的请求
+        let hash = expected.clone();
+        let input = password.to_string();
+        let ok = tokio::task::spawn_blocking(move || hash.matches(&input))
+            .await
+            .unwrap_or(false);
+        ok && enable
+    }
+
+    /// 明文的密码，用于 digest 鉴权，用户不存在、被禁用或者密码为哈希时返回 None
+    pub fn plain_password(&self, username: &str) -> Option<&str> {
+        match self.users.get(username) {
+            Some(User {
+                password: Password::Plain(password),
+                enable: true,
+                ..
+            }) => Some(password),
+            _ => None,
+        }
+    }
+
+    /// 用户是否存在，包括被禁用的用户
+    pub fn contains(&self, username: &str) -> bool {
+        self.users.contains_key(username)
+    }
+
+    /// 用户所在的组，用户不存在时为空
+    pub fn groups(&self, username: &str) -> &[String] {
+        match self.users.get(username) {
+            Some(user) => &user.groups,
+            None => &[],
+        }
+    }
+}
+
+// 没有用户使用 bcrypt 和 argon2 时，不存在的用户使用 SHA-1 验证
+impl Default for Password {
+    fn default() -> Self {
+        Password::Sha1(vec![0; 20])
+    }
+}
+
+impl Password {
+    // 验证密码，bcrypt 和 argon2 的计算比较慢
+    fn matches(&self, password: &str) -> bool {
+        match self {
+            Password::Plain(expected) => ct_eq(password.as_bytes(), expected.as_bytes()),
+            Password::Sha1(expected) => ct_eq(&Sha1::digest(password), expected),
+            Password::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Password::Argon2(hash) => match PasswordHash::new(hash) {
+                Ok(hash) => Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok(),
+                Err(_) => false,
+            },
+        }
+    }
+
+    // 根据前缀判断哈希的类型，不支持的哈希类型返回错误，其它的当作明文
+    fn parse(password: &str) -> Result<Password, String> {
+        if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| password.starts_with(prefix))
+        {
+            return match password.parse::<bcrypt::HashParts>() {
+                Ok(_) => Ok(Password::Bcrypt(password.to_string())),
+                Err(e) => Err(format!("invalid bcrypt hash: {}", e)),
+            };
+        }
+        if password.starts_with("$argon2") {
+            return match PasswordHash::new(password) {
+                Ok(_) => Ok(Password::Argon2(password.to_string())),
+                Err(e) => Err(format!("invalid argon2 hash: {}", e)),
+            };
+        }
+        if let Some(encoded) = password.strip_prefix("{SHA}") {
+            return match base64::decode(encoded) {
+                Ok(digest) if digest.len() == 20 => Ok(Password::Sha1(digest)),
+                _ => Err("invalid {SHA} hash".to_string()),
+            };
+        }
+        // htpasswd 默认的 MD5 以及 crypt 的哈希
+        if ["$apr1$", "$1$", "$5$", "$6$"]
+            .iter()
+            .any(|prefix| password.starts_with(prefix))
+        {
+            return Err("unsupported password hash, use bcrypt, argon2 or {SHA}".to_string());
+        }
+        Ok(Password::Plain(password.to_string()))
+    }
+}
+
+// 固定时间的比较，只有长度不同时会提前返回
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+// 重新加载配置时比较用户是否发生了变化
+impl PartialEq for UserStore {
+    fn eq(&self, other: &UserStore) -> bool {
+        self.users == other.users
+    }
+}
+
+// 不输出密码
+impl fmt::Debug for UserStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<_> = self.users.keys().collect();
+        names.sort();
+        f.debug_struct("UserStore").field("users", &names).finish()
+    }
+}
+
+#[tokio::test]
+async fn user_store_test() {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    let argon2 = Argon2::default()
+        .hash_password(b"secret", &SaltString::encode_b64(b"rust-proxy").unwrap())
+        .unwrap()
+        .to_string();
+    let path = std::env::temp_dir().join(format!("proxy-htpasswd-{}", std::process::id()));
+    fs::write(
+        &path,
+        format!(
+            "# users\nbcrypt:{}\n\nargon2:{}\n",
+            bcrypt::hash("secret", 4).unwrap(),
+            argon2
+        ),
+    )
+    .unwrap();
+
+    let user = |name: &str, password: &str, enable: bool| config::User {
+        name: name.to_string(),
+        password: password.to_string(),
+        enable,
+    };
+    let mut auth = config::Auth {
+        enable: true,
+        username: "rust".to_string(),
+        password: "proxy".to_string(),
+        users: vec![
+            user("sha", "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=", true),
+            user("disabled", "secret", false),
+        ],
+        file: Some(path.to_str().unwrap().to_string()),
+        groups: vec![config::Group {
+            name: "ops".to_string(),
+            users: vec!["rust".to_string(), "bcrypt".to_string()],
+        }],
+        ..config::Auth::default()
+    };
+    let store = UserStore::new(&auth).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(store.verify("rust", "proxy").await);
+    assert!(!store.verify("rust", "prox").await);
+    assert!(store.verify("sha", "secret").await);
+    assert!(!store.verify("sha", "Secret").await);
+    assert!(!store.verify("disabled", "secret").await);
+    assert!(!store.verify("nobody", "secret").await);
+    for name in ["bcrypt", "argon2"] {
+        assert!(store.verify(name, "secret").await);
+        assert!(!store.verify(name, "wrong").await);
+    }
+    // 不存在的用户使用已有用户的哈希验证，密码相同也不能通过
+    assert!(matches!(store.dummy, Password::Argon2(_)));
+    assert!(!store.verify("nobody", "secret").await);
+
+    assert_eq!(store.plain_password("rust"), Some("proxy"));
+    assert_eq!(store.plain_password("sha"), None);
+    assert_eq!(store.plain_password("disabled"), None);
+    assert_eq!(store.groups("bcrypt"), ["ops"]);
+    assert!(store.groups("sha").is_empty());
+    assert!(store.groups("nobody").is_empty());
+    assert!(store.contains("disabled"));
+    assert!(!store.contains("nobody"));
+
+    // 没有开启鉴权时不读取文件
+    auth.enable = false;
+    assert!(UserStore::new(&auth).unwrap().users.is_empty());
+
+    auth.enable = true;
+    auth.file = None;
+    auth.users.push(user("rust", "other", true));
+    assert!(UserStore::new(&auth)
+        .unwrap_err()
+        .contains("duplicate user `rust`"));
+    auth.users.pop();
+    auth.users.push(user("md5", "$apr1$salt$hash", true));
+    assert!(UserStore::new(&auth).unwrap_err().contains("unsupported"));
+    auth.users.pop();
+    auth.users.push(user("bcrypt", "$2y$10$short", true));
+    assert!(UserStore::new(&auth)
+        .unwrap_err()
+        .contains("invalid bcrypt hash"));
+    auth.users.pop();
+    auth.groups[0].users = vec!["rust".to_string(), "nobody".to_string()];
+    assert!(UserStore::new(&auth)
+        .unwrap_err()
+        .contains("group `ops`: unknown user `nobody`"));
+    auth.groups.clear();
+
+    // digest 只能使用明文的密码
+    auth.scheme = config::Scheme::Digest;
+    assert!(UserStore::new(&auth)
+        .unwrap_err()
+        .contains("user `sha`: digest auth requires a plaintext password"));
+    auth.users.clear();
+    assert!(UserStore::new(&auth).is_ok());
+    auth.realm = "rust\"proxy".to_string();
+    assert!(UserStore::new(&auth).unwrap_err().contains("invalid realm"));
+}
@@ -2,73 +2,545 @@
 
 use std::error::Error;
 use std::fs::{self, File};
+use std::path::Path;
 
-use serde::{Deserialize, Serialize};
+use log::info;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::{self, Result as JsonResult};
 use serde_yaml;
 
+use crate::filter::CompiledRuleSet;
+
 const FILENAME: &str = "config.yml";
 
 /// 配置文件内容
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct Config {
     pub server: Server,
+    #[serde(default)]
+    pub policy: Policy,
     pub deny: DenyConfig,
+    #[serde(default)]
+    pub allow: AllowConfig,
+}
+
+/// 过滤策略
+///
+/// allow 规则作为例外首先匹配，然后匹配 deny 规则，
+/// 同一部分中按照配置的顺序匹配，第一个匹配的规则决定动作，
+/// 所有规则都不匹配时使用 default
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Policy {
+    #[serde(default)]
+    pub default: Action,
+    /// 规则没有配置 block 以及 default 为 deny 时返回给客户端的内容，
+    /// 没有配置时返回不带实体内容的 403 Forbidden
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<Block>,
+    /// 所有规则以及 default 的模式，规则中配置的 mode 优先
+    #[serde(default)]
+    pub mode: Mode,
+}
+
+/// 规则的模式
+///
+/// audit 模式的规则匹配之后只记录日志，继续匹配后面的规则，用于在生效之前验证规则
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Enforce,
+    Audit,
+}
+
+/// 拒绝请求时返回给客户端的响应
+///
+/// 模板中可以使用 `{{rule}}`、`{{url}}`、`{{method}}`、`{{host}}`、
+/// `{{client}}`、`{{request_id}}` 以及 `{{status}}`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Block {
+    #[serde(default = "default_block_status")]
+    pub status: u16,
+    /// 没有配置 Content-Type 时使用 `text/html; charset=utf-8`
+    #[serde(default)]
+    pub headers: Vec<Header>,
+    /// 实体内容的模板文件，相对路径相对于配置文件所在的目录，没有配置时使用内置的页面
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+impl Default for Block {
+    fn default() -> Self {
+        Block {
+            status: default_block_status(),
+            headers: Vec::new(),
+            file: None,
+        }
+    }
+}
+
+fn default_block_status() -> u16 {
+    403
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+/// 规则匹配之后的动作
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Server {
     pub auth: Auth,
+    /// 客户端连接空闲多少秒之后关闭，0 表示不限制
+    #[serde(default = "default_keep_alive_timeout")]
+    pub keep_alive_timeout: u64,
+    #[serde(default)]
+    pub upstream: Upstream,
+    /// tunnel 两个方向都没有数据多少秒之后关闭，0 表示不限制
+    #[serde(default = "default_tunnel_idle_timeout")]
+    pub tunnel_idle_timeout: u64,
+    #[serde(default)]
+    pub acl: Acl,
+    /// 检查实体内容时最多读取的字节数，超过的部分不检查，直接转发
+    #[serde(default = "default_max_inspect_body_size")]
+    pub max_inspect_body_size: usize,
+}
+
+/// 客户端地址访问控制，支持 IPv4 和 IPv6 的 CIDR 以及单独的 IP 地址
+///
+/// 在 deny 中的地址被拒绝；allow 不为空时，只接受 allow 中的地址
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Acl {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// 到目的服务器的连接池配置
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Upstream {
+    /// 每个目的地址最多保存的空闲连接数量，0 表示不使用连接池
+    pub max_idle_per_host: usize,
+    /// 空闲连接多少秒之后过期
+    pub idle_timeout: u64,
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Upstream {
+            max_idle_per_host: 8,
+            idle_timeout: 60,
+        }
+    }
+}
+
+fn default_keep_alive_timeout() -> u64 {
+    60
+}
+
+fn default_tunnel_idle_timeout() -> u64 {
+    300
+}
+
+fn default_max_inspect_body_size() -> usize {
+    1024 * 1024
 }
 
 /// 代理验证需要的用户名和密码
-#[derive(Default, Serialize, Deserialize, Debug)]
+///
+/// username 和 password 配置一个用户，users 和 file 可以配置多个用户，三者可以同时使用，
+/// 密码可以是明文，也可以是 htpasswd 格式的 bcrypt、argon2 或者 `{SHA}` 哈希
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Auth {
     pub enable: bool,
+    /// 鉴权方式，digest 只能使用明文的密码
+    #[serde(default)]
+    pub scheme: Scheme,
+    /// 407 响应中的 realm，digest 计算时也会使用
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// digest 的 nonce 多少秒之后过期，过期之后客户端需要使用新的 nonce
+    #[serde(default = "default_nonce_timeout")]
+    pub nonce_timeout: u64,
+    #[serde(default)]
     pub username: String,
+    #[serde(default)]
     pub password: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<User>,
+    /// htpasswd 格式的文件，每行为 `用户名:密码`，相对路径相对于配置文件所在的目录
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// 用户组，规则可以只对某些组中的用户生效
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<Group>,
+    /// 使用 `Proxy-Authorization: Bearer` 携带的 JWT 鉴权，与 scheme 同时生效
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwt: Option<Jwt>,
+    /// 鉴权失败的限制
+    #[serde(default)]
+    pub lockout: Lockout,
+}
+
+/// 鉴权失败的限制
+///
+/// 同一个客户端地址或者用户名连续失败 max_failures 次之后暂时锁定，锁定期间返回 429，
+/// 锁定的时间从 base_delay 开始，之后每次失败翻倍，最长为 max_delay
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Lockout {
+    /// 0 表示不限制
+    #[serde(default = "default_max_failures")]
+    pub max_failures: u32,
+    /// 单位为秒
+    #[serde(default = "default_base_delay")]
+    pub base_delay: u64,
+    #[serde(default = "default_max_delay")]
+    pub max_delay: u64,
+    /// 多少秒没有失败之后重新计数
+    #[serde(default = "default_reset_after")]
+    pub reset_after: u64,
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Lockout {
+            max_failures: default_max_failures(),
+            base_delay: default_base_delay(),
+            max_delay: default_max_delay(),
+            reset_after: default_reset_after(),
+        }
+    }
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+fn default_max_failures() -> u32 {
+    5
+}
+
+fn default_base_delay() -> u64 {
+    1
+}
+
+fn default_max_delay() -> u64 {
+    900
+}
+
+fn default_reset_after() -> u64 {
+    900
+}
+
+/// JWT 鉴权
+///
+/// token 使用 keys 中 algorithm 相同的密钥验证签名，必须包含 exp，
+/// 配置了 issuer 或者 audience 时 token 中必须包含对应的 iss 或者 aud
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Jwt {
+    pub keys: Vec<JwtKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    /// 没有配置时拒绝包含 aud 的 token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    /// 检查 exp 和 nbf 时允许的时钟误差，单位为秒
+    #[serde(default = "default_jwt_leeway")]
+    pub leeway: u64,
+    /// 作为用户名的 claim
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+    /// 作为用户组的 claim，值可以是字符串或者字符串数组
+    #[serde(default = "default_groups_claim")]
+    pub groups_claim: String,
+}
+
+/// 验证 JWT 签名的密钥，secret 和 public_key 只能配置一个
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct JwtKey {
+    /// 只验证头部中 kid 相同的 token，没有配置时不限制
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    /// HS256、HS384、HS512、RS256、RS384、RS512、PS256、PS384 或者 PS512
+    pub algorithm: String,
+    /// HMAC 的密钥
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// PEM 格式的 RSA 公钥文件，相对路径相对于配置文件所在的目录
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+}
+
+fn default_jwt_leeway() -> u64 {
+    60
+}
+
+fn default_username_claim() -> String {
+    "sub".to_string()
+}
+
+fn default_groups_claim() -> String {
+    "groups".to_string()
+}
+
+/// 用户组，users 中的用户需要在 username、users 或者 file 中配置
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Group {
+    pub name: String,
+    pub users: Vec<String>,
+}
+
+/// 代理鉴权的方式
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    #[default]
+    Basic,
+    /// RFC 7616，支持 MD5 以及 SHA-256
+    Digest,
+}
+
+fn default_realm() -> String {
+    "rust-proxy".to_string()
+}
+
+fn default_nonce_timeout() -> u64 {
+    300
+}
+
+/// 可以使用代理的用户
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct User {
+    pub name: String,
+    pub password: String,
+    /// 为 false 时不能使用代理，不需要删除用户就可以临时禁用
+    #[serde(default = "default_user_enable")]
+    pub enable: bool,
+}
+
+fn default_user_enable() -> bool {
+    true
+}
+
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
 pub struct DenyConfig {
     #[serde(default)]
     pub request: Vec<Request>,
+    #[serde(default)]
     pub response: Vec<Response>,
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+/// 允许通过的规则，与 deny 的格式相同
+pub type AllowConfig = DenyConfig;
+
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Request {
     pub name: String,
     pub rule: RequestDeny,
+    /// 被拒绝时返回给客户端的内容，只用于 deny 中的规则
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<Block>,
+    /// 没有配置时使用 policy.mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<Mode>,
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Response {
     pub name: String,
     pub rule: ResponseDeny,
+    /// 被拒绝时返回给客户端的内容，只用于 deny 中的规则
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<Block>,
+    /// 没有配置时使用 policy.mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<Mode>,
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+/// 请求规则
+///
+/// line、hosts、headers、expr 以及 body 全部满足才算匹配，没有配置的部分不做限制
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
 pub struct RequestDeny {
+    #[serde(default)]
     pub line: RequestLine,
+    /// 目的主机，满足其一即可，CONNECT 请求同样适用
+    ///
+    /// 支持 `example.com`、`*.example.com`、`.example.com` 以及 `~正则表达式`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+    #[serde(default)]
     pub headers: Vec<Header>,
+    /// 组合条件
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expr: Option<Expr>,
+    /// 实体内容
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<RequestBody>,
+    /// 只对这些用户生效，与 groups 满足其一即可，都没有配置时对所有请求生效
+    ///
+    /// 配置了 users 或者 groups 的规则不匹配没有通过鉴权的请求
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+    /// 只对这些组中的用户生效
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+}
+
+/// 请求实体内容的匹配规则，配置的部分全部满足才算匹配
+///
+/// 只读取前 `server.max_inspect_body_size` 个字节，
+/// regex 和 contains 只检查读取到的部分，json 和 form 只检查完整的实体内容
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
+pub struct RequestBody {
+    /// 实体内容超过 max_size 个字节时匹配，需要小于 `server.max_inspect_body_size`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// 字节正则表达式，与 contains 满足其一即可
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub regex: Vec<String>,
+    /// 包含的字符串，区分大小写
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub contains: Vec<String>,
+    /// Content-Type 为 application/json 时 JSON pointer 对应的值，全部满足
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub json: Vec<JsonField>,
+    /// Content-Type 为 application/x-www-form-urlencoded 时的表单字段，全部满足
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub form: Vec<FormField>,
+}
+
+/// JSON pointer（如 `/user/role`）对应的值与 value 相等
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct JsonField {
+    pub pointer: String,
+    pub value: serde_json::Value,
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+/// 名字为 name 的表单字段的值（解码之后）满足正则表达式 value
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
+pub struct FormField {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
 pub struct RequestLine {
+    #[serde(default)]
     pub methods: Vec<String>,
+    #[serde(default)]
     pub path: Vec<String>,
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+/// 请求的组合条件，可以任意嵌套
+///
+/// ```yaml
+/// expr:
+///   all:
+///     - path: ^/admin
+///     - not:
+///         header: { key: X-Ops, value: "true" }
+/// ```
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Expr {
+    /// 全部满足
+    All(Vec<Expr>),
+    /// 满足其一
+    Any(Vec<Expr>),
+    /// 不满足
+    Not(Box<Expr>),
+    /// 请求方法，不区分大小写
+    Method(String),
+    /// 请求路径，支持正则匹配
+    Path(String),
+    /// 头部的值包含 value
+    Header(Header),
+    /// 目的主机名，不包含端口，支持正则匹配
+    Host(String),
+    /// 客户端地址，支持 CIDR 以及单独的 IP 地址
+    Client(String),
+    /// JWT 中的 claim，值支持正则匹配，数组中任意一个元素匹配即可
+    Claim(Claim),
+}
+
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Claim {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Header {
     pub key: String,
     pub value: String,
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
+/// 响应规则
+///
+/// status、content_length、headers 以及 body 全部满足才算匹配，没有配置的部分不做限制
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
 pub struct ResponseDeny {
+    #[serde(default)]
     pub headers: Vec<Header>,
+    /// 状态码，满足其一即可，支持 `404`、`5xx` 以及 `500-599`
+    #[serde(
+        default,
+        deserialize_with = "status_codes",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub status: Vec<String>,
+    /// Content-Length 的范围，没有 Content-Length 头部的响应不匹配
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_length: Option<LengthRange>,
+    /// 实体内容，chunked 编码会先被解码
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<BodyPattern>,
+}
+
+/// 长度范围，包含边界，没有配置的一端不做限制
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
+pub struct LengthRange {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<u64>,
+}
+
+/// 实体内容的匹配规则，regex 和 contains 中满足其一即可
+///
+/// 只检查前 `server.max_inspect_body_size` 个字节
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq)]
+pub struct BodyPattern {
+    /// 字节正则表达式，可以使用 `(?-u:\xff)` 匹配任意字节
+    #[serde(default)]
+    pub regex: Vec<String>,
+    /// 包含的字符串，区分大小写
+    #[serde(default)]
+    pub contains: Vec<String>,
+}
+
+// 状态码可以写成数字或者字符串，统一转换成字符串
+fn status_codes<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Status {
+        Code(u16),
+        Text(String),
+    }
+
+    let codes = Vec::<Status>::deserialize(deserializer)?;
+    Ok(codes
+        .into_iter()
+        .map(|status| match status {
+            Status::Code(code) => code.to_string(),
+            Status::Text(text) => text,
+        })
+        .collect())
 }
 
 impl Config {
@@ -81,11 +553,100 @@ impl Config {
         let config: Config = match serde_yaml::from_reader(f) {
             Ok(r) => r,
             Err(e) => {
-                println!("{}", &e);
+                info!("deserialize config file {} failed: {}", filepath, e);
                 return Err("deserialize config file failed");
             }
         };
-        return Ok(config);
+        Ok(config)
+    }
+
+    /// 读取并检查配置文件，用于启动时加载配置
+    ///
+    /// 与 parse 不同，返回的错误信息中包含文件路径和具体的原因
+    pub fn load(filepath: &str) -> Result<Config, String> {
+        Config::load_with_rules(filepath).map(|(config, _)| config)
+    }
+
+    /// 读取配置文件，同时返回检查配置时编译好的规则
+    ///
+    /// 重新加载配置时直接使用返回的规则，配置中引用的文件只读取一次
+    pub fn load_with_rules(filepath: &str) -> Result<(Config, CompiledRuleSet), String> {
+        let f = match File::open(filepath) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("can not open config file {}: {}", filepath, e)),
+        };
+        let mut config: Config = match serde_yaml::from_reader(f) {
+            Ok(r) => r,
+            Err(e) => {
+                return Err(format!(
+                    "deserialize config file {} failed: {}",
+                    filepath, e
+                ))
+            }
+        };
+        if let Some(dir) = Path::new(filepath).parent() {
+            config.resolve_paths(dir);
+        }
+        match config.validate() {
+            Ok(rules) => Ok((config, rules)),
+            Err(e) => Err(format!("invalid config file {}: {}", filepath, e)),
+        }
+    }
+
+    // 配置中引用的文件使用相对于配置文件所在目录的路径
+    fn resolve_paths(&mut self, dir: &Path) {
+        let resolve_file = |file: &mut String| {
+            if Path::new(file.as_str()).is_relative() {
+                *file = dir.join(&file).to_string_lossy().into_owned();
+            }
+        };
+        let resolve = |block: &mut Option<Block>| {
+            if let Some(Block {
+                file: Some(file), ..
+            }) = block
+            {
+                resolve_file(file);
+            }
+        };
+        if let Some(file) = &mut self.server.auth.file {
+            resolve_file(file);
+        }
+        if let Some(jwt) = &mut self.server.auth.jwt {
+            jwt.keys
+                .iter_mut()
+                .filter_map(|k| k.public_key.as_mut())
+                .for_each(resolve_file);
+        }
+        resolve(&mut self.policy.block);
+        for section in [&mut self.deny, &mut self.allow] {
+            section
+                .request
+                .iter_mut()
+                .for_each(|r| resolve(&mut r.block));
+            section
+                .response
+                .iter_mut()
+                .for_each(|r| resolve(&mut r.block));
+        }
+    }
+
+    /// 检查配置内容是否合法，返回编译好的规则
+    pub fn validate(&self) -> Result<CompiledRuleSet, String> {
+        let auth = &self.server.auth;
+        if auth.enable
+            && auth.username.is_empty()
+            && auth.users.is_empty()
+            && auth.file.is_none()
+            && auth.jwt.is_none()
+        {
+            return Err(
+                "server.auth.username, users, file or jwt is required when auth is enabled"
+                    .to_string(),
+            );
+        }
+
+        // 编译一遍规则，检查正则表达式等是否有误
+        CompiledRuleSet::new(self)
     }
 
     /// 生成默认的配置文件
@@ -95,13 +656,28 @@ impl Config {
             server: Server {
                 auth: Auth {
                     enable: false,
+                    scheme: Scheme::Basic,
+                    realm: default_realm(),
+                    nonce_timeout: default_nonce_timeout(),
                     username: "".to_string(),
                     password: "".to_string(),
+                    users: Vec::new(),
+                    file: None,
+                    groups: Vec::new(),
+                    jwt: None,
+                    lockout: Lockout::default(),
                 },
+                keep_alive_timeout: default_keep_alive_timeout(),
+                upstream: Upstream::default(),
+                tunnel_idle_timeout: default_tunnel_idle_timeout(),
+                acl: Acl::default(),
+                max_inspect_body_size: default_max_inspect_body_size(),
             },
+            policy: Policy::default(),
             deny: DenyConfig {
                 ..DenyConfig::default()
             },
+            allow: AllowConfig::default(),
         };
 
         // 结构体转换成对应的字符串
@@ -131,4 +707,117 @@ fn parse_config_test() {
     assert!(config.server.auth.enable);
     assert_eq!(config.server.auth.username, "rust");
     assert_eq!(config.server.auth.password, "proxy");
+    assert_eq!(config.server.auth.users.len(), 2);
+    assert!(config.server.auth.users[0].enable);
+    assert!(!config.server.auth.users[1].enable);
+    assert_eq!(config.server.auth.groups[0].users, vec!["alice"]);
+    let jwt = config.server.auth.jwt.as_ref().unwrap();
+    assert_eq!(jwt.keys[0].kid.as_deref(), Some("2024"));
+    assert_eq!(jwt.audience.as_deref(), Some("rust-proxy"));
+    assert_eq!(jwt.leeway, 60);
+    assert_eq!(jwt.username_claim, "sub");
+    let lockout = &config.server.auth.lockout;
+    assert_eq!((lockout.max_failures, lockout.base_delay), (3, 2));
+    assert_eq!((lockout.max_delay, lockout.reset_after), (900, 900));
+    assert_eq!(config.server.keep_alive_timeout, 30);
+    assert_eq!(config.server.upstream.max_idle_per_host, 4);
+    assert_eq!(config.server.upstream.idle_timeout, 60);
+    assert_eq!(config.server.tunnel_idle_timeout, 120);
+    assert_eq!(
+        config.server.acl.allow,
+        vec!["127.0.0.1", "10.0.0.0/8", "::1"]
+    );
+    assert_eq!(config.server.acl.deny, vec!["10.0.0.1"]);
+    assert_eq!(config.server.max_inspect_body_size, 64 * 1024);
+    assert_eq!(config.policy.default, Action::Allow);
+    assert_eq!(config.policy.mode, Mode::Enforce);
+    assert_eq!(config.allow.request.len(), 1);
+    assert_eq!(
+        config.deny.request[2].rule.hosts,
+        vec!["example.com", "*.ads.net", ".tracker.org", r"~^cdn[0-9]+\."]
+    );
+    assert_eq!(
+        config.deny.request[1].rule.expr,
+        Some(Expr::All(vec![
+            Expr::Host(r"^admin\.".to_string()),
+            Expr::Not(Box::new(Expr::Client("127.0.0.1".to_string()))),
+        ]))
+    );
+    let block = config.deny.request[2].block.as_ref().unwrap();
+    assert_eq!(block.status, 451);
+    assert_eq!(block.file, None);
+    let body = config.deny.request[3].rule.body.as_ref().unwrap();
+    assert_eq!(body.max_size, Some(16));
+    assert_eq!(body.json[0].pointer, "/user/role");
+    assert_eq!(body.json[0].value, serde_json::json!("admin"));
+    assert_eq!(body.form[0].name, "token");
+    let contractors = &config.deny.request[4].rule;
+    assert_eq!(contractors.users, vec!["bob"]);
+    assert_eq!(contractors.groups, vec!["contractors"]);
+    // 数字和字符串形式的状态码
+    assert_eq!(config.deny.response[1].mode, Some(Mode::Audit));
+    let stack_trace = &config.deny.response[1].rule;
+    assert_eq!(stack_trace.status, vec!["500", "5xx", "400-403"]);
+    assert_eq!(
+        stack_trace.content_length,
+        Some(LengthRange {
+            min: None,
+            max: Some(1048576)
+        })
+    );
+    assert_eq!(
+        stack_trace.body.as_ref().unwrap().contains,
+        vec!["Traceback (most recent call last)"]
+    );
+}
+
+#[test]
+fn validate_config_test() {
+    assert!(Config::load("test/not_exist.yml")
+        .unwrap_err()
+        .contains("test/not_exist.yml"));
+
+    let config = Config::load("test/config.yml").unwrap();
+    assert!(config.validate().is_ok());
+    let jwt = config.server.auth.jwt.as_ref().unwrap();
+    assert_eq!(
+        jwt.keys[0].public_key.as_deref(),
+        Some(Path::new("test").join("jwt-public.pem").to_str().unwrap())
+    );
+
+    let mut config = Config::default();
+    config.server.auth.enable = true;
+    assert!(config.validate().is_err());
+
+    let mut config = Config::default();
+    config.deny.request.push(Request {
+        name: "bad path".to_string(),
+        rule: RequestDeny {
+            line: RequestLine {
+                methods: vec![],
+                path: vec!["(".to_string()],
+            },
+            headers: vec![],
+            hosts: vec![],
+            expr: None,
+            body: None,
+            users: vec![],
+            groups: vec![],
+        },
+        block: None,
+        mode: None,
+    });
+    assert!(config.validate().unwrap_err().contains("bad path"));
+
+    let mut config = Config::default();
+    config.allow.request.push(Request {
+        name: "bad client".to_string(),
+        rule: RequestDeny {
+            expr: Some(Expr::Not(Box::new(Expr::Client("local".to_string())))),
+            ..RequestDeny::default()
+        },
+        block: None,
+        mode: None,
+    });
+    assert!(config.validate().unwrap_err().contains("bad client"));
 }
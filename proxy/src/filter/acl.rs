@@ -0,0 +1,96 @@
+//! 客户端地址访问控制
+//!
+//! 在读取任何请求数据之前，根据客户端的地址决定是否接受连接
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use super::FilterStatus;
+use crate::config;
+
+/// 编译之后的访问控制列表
+#[derive(Debug, Default)]
+pub struct Acl {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl Acl {
+    pub fn new(acl: &config::Acl) -> Result<Acl, String> {
+        Ok(Acl {
+            allow: parse_nets(&acl.allow).map_err(|e| format!("server.acl.allow: {}", e))?,
+            deny: parse_nets(&acl.deny).map_err(|e| format!("server.acl.deny: {}", e))?,
+        })
+    }
+
+    /// 检查客户端地址
+    ///
+    /// 在 deny 中的地址被拒绝；allow 不为空时，只接受 allow 中的地址
+    pub fn check(&self, addr: IpAddr) -> FilterStatus {
+        let addr = normalize(addr);
+        if self.deny.iter().any(|net| net.contains(&addr)) {
+            return FilterStatus::Reject;
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|net| net.contains(&addr)) {
+            return FilterStatus::Reject;
+        }
+        FilterStatus::Forward
+    }
+}
+
+/// 解析 CIDR，单独的 IP 地址视为只包含它自己的网段
+pub fn parse_net(net: &str) -> Result<IpNet, String> {
+    if let Ok(net) = net.parse::<IpNet>() {
+        return Ok(net);
+    }
+    match net.parse::<IpAddr>() {
+        Ok(addr) => Ok(IpNet::from(addr)),
+        Err(_) => Err(format!("invalid address `{}`", net)),
+    }
+}
+
+fn parse_nets(nets: &[String]) -> Result<Vec<IpNet>, String> {
+    nets.iter().map(|net| parse_net(net)).collect()
+}
+
+/// IPv4 映射的 IPv6 地址（监听 `::` 时）转换成 IPv4 地址，便于使用 IPv4 的网段匹配
+pub fn normalize(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => addr,
+        },
+        v4 => v4,
+    }
+}
+
+#[test]
+fn acl_test() {
+    let check = |acl: &Acl, addr: &str| acl.check(addr.parse().unwrap());
+
+    // 默认接受所有的地址
+    let acl = Acl::default();
+    assert_eq!(check(&acl, "1.2.3.4"), FilterStatus::Forward);
+
+    let acl = Acl::new(&config::Acl {
+        allow: vec!["10.0.0.0/8".to_string(), "fd00::/8".to_string()],
+        deny: vec!["10.0.0.1".to_string()],
+    })
+    .unwrap();
+    assert_eq!(check(&acl, "10.1.2.3"), FilterStatus::Forward);
+    assert_eq!(check(&acl, "fd00::1"), FilterStatus::Forward);
+    assert_eq!(check(&acl, "::ffff:10.1.2.3"), FilterStatus::Forward);
+    // deny 优先
+    assert_eq!(check(&acl, "10.0.0.1"), FilterStatus::Reject);
+    // 不在 allow 中
+    assert_eq!(check(&acl, "192.168.1.1"), FilterStatus::Reject);
+    assert_eq!(check(&acl, "::1"), FilterStatus::Reject);
+
+    let err = Acl::new(&config::Acl {
+        allow: vec![],
+        deny: vec!["10.0.0.0/33".to_string()],
+    })
+    .unwrap_err();
+    assert!(err.contains("server.acl.deny"));
+}
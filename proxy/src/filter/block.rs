@@ -0,0 +1,223 @@
+//! 拒绝请求时返回给客户端的页面
+//!
+//! 模板只在加载配置的时候读取一次，`{{name}}` 形式的占位符在拒绝请求时替换
+
+use std::fs;
+
+use crate::config::{self, Header};
+
+// 没有配置模板文件时使用的页面
+const DEFAULT_TEMPLATE: &str = "<!DOCTYPE html>
+<html>
+<head><title>{{status}} Blocked</title></head>
+<body>
+<h1>Request blocked</h1>
+<p>{{method}} {{url}} was blocked by rule <code>{{rule}}</code>.</p>
+<p>Request ID: <code>{{request_id}}</code></p>
+</body>
+</html>
+";
+
+// 由代理设置的头部，不能在配置中指定
+const RESERVED_HEADERS: [&str; 3] = ["Content-Length", "Transfer-Encoding", "Connection"];
+
+/// 编译之后的拒绝页面
+#[derive(Debug, PartialEq)]
+pub struct BlockPage {
+    status: u16,
+    headers: Vec<Header>,
+    template: String,
+}
+
+impl BlockPage {
+    pub fn new(block: &config::Block) -> Result<BlockPage, String> {
+        // 拒绝页面不能看起来像成功的响应，CONNECT 收到 2xx 会认为隧道已经建立，3xx 需要 Location
+        if !(400..=599).contains(&block.status) {
+            return Err(format!("invalid block status {}", block.status));
+        }
+        for Header { key, .. } in block.headers.iter() {
+            if key.is_empty() {
+                return Err("empty block header key".to_string());
+            }
+            if RESERVED_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(key)) {
+                return Err(format!("block header `{}` is set by the proxy", key));
+            }
+        }
+
+        let mut headers = block.headers.clone();
+        if !headers
+            .iter()
+            .any(|h| h.key.eq_ignore_ascii_case("Content-Type"))
+        {
+            headers.insert(
+                0,
+                Header {
+                    key: "Content-Type".to_string(),
+                    value: "text/html; charset=utf-8".to_string(),
+                },
+            );
+        }
+        let template = match &block.file {
+            Some(file) => match fs::read_to_string(file) {
+                Ok(template) => template,
+                Err(e) => return Err(format!("read block page {} failed: {}", file, e)),
+            },
+            None => DEFAULT_TEMPLATE.to_string(),
+        };
+
+        Ok(BlockPage {
+            status: block.status,
+            headers,
+            template,
+        })
+    }
+
+    /// 替换模板中的占位符，生成完整的 HTTP 响应
+    ///
+    /// HTML 页面中替换的内容会被转义，没有提供的占位符保持原样
+    pub fn render(&self, vars: &[(&str, &str)]) -> Vec<u8> {
+        let html = self.headers.iter().any(|h| {
+            h.key.eq_ignore_ascii_case("Content-Type")
+                && h.value.to_ascii_lowercase().contains("html")
+        });
+        let status = self.status.to_string();
+        let lookup = |name: &str| match name {
+            "status" => Some(status.as_str()),
+            _ => vars.iter().find(|(key, _)| *key == name).map(|(_, v)| *v),
+        };
+
+        // 只扫描一遍模板，替换进去的内容不会再被当作占位符
+        let mut body = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find("{{") {
+            body.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let end = match after.find("}}") {
+                Some(end) => end,
+                None => {
+                    rest = &rest[start..];
+                    break;
+                }
+            };
+            match lookup(after[..end].trim()) {
+                Some(value) if html => body.push_str(&escape_html(value)),
+                Some(value) => body.push_str(value),
+                None => body.push_str(&rest[start..start + end + 4]),
+            }
+            rest = &after[end + 2..];
+        }
+        body.push_str(rest);
+
+        let mut response = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for Header { key, value } in self.headers.iter() {
+            response.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        response.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        ));
+        response.push_str(&body);
+        response.into_bytes()
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+// 状态码对应的描述，不常见的状态码使用 Blocked
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        301 => "Moved Permanently",
+        302 => "Found",
+        307 => "Temporary Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Blocked",
+    }
+}
+
+#[test]
+fn block_page_test() {
+    let path = std::env::temp_dir().join(format!("proxy-block-{}.html", std::process::id()));
+    fs::write(&path, "<p>{{ rule }} {{url}} {{status}} {{unknown}} {{</p>").unwrap();
+
+    let page = BlockPage::new(&config::Block {
+        status: 451,
+        headers: vec![Header {
+            key: "X-Blocked-By".to_string(),
+            value: "proxy".to_string(),
+        }],
+        file: Some(path.to_str().unwrap().to_string()),
+    })
+    .unwrap();
+    let response = page.render(&[("rule", "{{url}}"), ("url", "http://a/?q=<script>")]);
+    let response = String::from_utf8(response).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 451 Unavailable For Legal Reasons\r\n"));
+    assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+    assert!(response.contains("X-Blocked-By: proxy\r\n"));
+    // 替换的内容会被转义，并且不会被再次替换
+    let body = "<p>{{url}} http://a/?q=&lt;script&gt; 451 {{unknown}} {{</p>";
+    assert!(response.ends_with(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )));
+
+    // 纯文本不转义
+    let page = BlockPage::new(&config::Block {
+        headers: vec![Header {
+            key: "content-type".to_string(),
+            value: "text/plain".to_string(),
+        }],
+        ..config::Block::default()
+    })
+    .unwrap();
+    let response = String::from_utf8(page.render(&[("url", "<a>")])).unwrap();
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    assert!(response.contains("<a>"));
+
+    let invalid = |block: config::Block| BlockPage::new(&block).is_err();
+    for status in [101, 200, 204, 302, 304, 600] {
+        assert!(invalid(config::Block {
+            status,
+            ..config::Block::default()
+        }));
+    }
+    assert!(invalid(config::Block {
+        headers: vec![Header {
+            key: "content-length".to_string(),
+            value: "1".to_string(),
+        }],
+        ..config::Block::default()
+    }));
+    assert!(invalid(config::Block {
+        file: Some("test/not_exist.html".to_string()),
+        ..config::Block::default()
+    }));
+}
@@ -0,0 +1,357 @@
+//! 实体内容过滤
+//!
+//! 实体内容只读取开头的一部分（`server.max_inspect_body_size`），
+//! 正则表达式以及字符串只检查读取到的部分，json 和 form 只检查完整的实体内容
+
+use std::io::Read;
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use regex::{bytes, Regex};
+use serde_json::Value;
+
+use super::InspectedBody;
+use crate::config::RequestBody;
+use crate::http;
+
+/// 编译之后的请求实体内容规则
+#[derive(Debug)]
+pub struct CompiledBody {
+    max_size: Option<u64>,
+    // contains 转义之后和 regex 编译在一起
+    patterns: Option<bytes::RegexSet>,
+    json: Vec<(String, Value)>,
+    form: Vec<(String, Regex)>,
+}
+
+impl CompiledBody {
+    /// limit 为 `server.max_inspect_body_size`，max_size 需要小于 limit，
+    /// 这样没有读取完整的实体内容一定超过了 max_size
+    pub fn new(body: &RequestBody, limit: usize) -> Result<CompiledBody, String> {
+        if body.max_size.is_none()
+            && body.regex.is_empty()
+            && body.contains.is_empty()
+            && body.json.is_empty()
+            && body.form.is_empty()
+        {
+            return Err("empty body rule".to_string());
+        }
+        if let Some(max_size) = body.max_size {
+            if max_size >= limit as u64 {
+                return Err(format!(
+                    "body.max_size {} must be less than server.max_inspect_body_size {}",
+                    max_size, limit
+                ));
+            }
+        }
+
+        let mut json = Vec::new();
+        for field in body.json.iter() {
+            // JSON pointer 为空（整个文档）或者以 / 开头
+            if !field.pointer.is_empty() && !field.pointer.starts_with('/') {
+                return Err(format!("invalid json pointer `{}`", field.pointer));
+            }
+            json.push((field.pointer.clone(), field.value.clone()));
+        }
+        let mut form = Vec::new();
+        for field in body.form.iter() {
+            if field.name.is_empty() {
+                return Err("empty form field name".to_string());
+            }
+            match Regex::new(&field.value) {
+                Ok(reg) => form.push((field.name.clone(), reg)),
+                Err(e) => return Err(format!("invalid form regex `{}`: {}", field.value, e)),
+            }
+        }
+
+        Ok(CompiledBody {
+            max_size: body.max_size,
+            patterns: compile_patterns(&body.regex, &body.contains)?,
+            json,
+            form,
+        })
+    }
+
+    /// 请求的实体内容是否满足规则，配置的部分全部满足才算匹配
+    pub fn is_match(&self, request: &http::Request, body: &InspectedBody) -> bool {
+        if let Some(max_size) = self.max_size {
+            let exceeded = match request.body {
+                http::Body::Length(length) => length > max_size,
+                _ => !body.complete || body.data.len() as u64 > max_size,
+            };
+            if !exceeded {
+                return false;
+            }
+        }
+        if let Some(patterns) = &self.patterns {
+            if !patterns.is_match(body.data) {
+                return false;
+            }
+        }
+        if !self.json.is_empty() && !self.match_json(request, body) {
+            return false;
+        }
+        if !self.form.is_empty() && !self.match_form(request, body) {
+            return false;
+        }
+        true
+    }
+
+    fn match_json(&self, request: &http::Request, body: &InspectedBody) -> bool {
+        if !body.complete || !is_json(request) {
+            return false;
+        }
+        let value: Value = match serde_json::from_slice(body.data) {
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+        self.json
+            .iter()
+            .all(|(pointer, expected)| value.pointer(pointer) == Some(expected))
+    }
+
+    fn match_form(&self, request: &http::Request, body: &InspectedBody) -> bool {
+        if !body.complete || media_type(request) != Some("application/x-www-form-urlencoded") {
+            return false;
+        }
+        let fields = parse_form(body.data);
+        self.form.iter().all(|(name, reg)| {
+            fields
+                .iter()
+                .any(|(key, value)| key == name && reg.is_match(value))
+        })
+    }
+}
+
+/// 编译字节正则表达式以及需要包含的字符串，两者都为空时返回 None
+pub fn compile_patterns(
+    regex: &[String],
+    contains: &[String],
+) -> Result<Option<bytes::RegexSet>, String> {
+    if regex.is_empty() && contains.is_empty() {
+        return Ok(None);
+    }
+    for pattern in regex.iter() {
+        if let Err(e) = bytes::Regex::new(pattern) {
+            return Err(format!("invalid body regex `{}`: {}", pattern, e));
+        }
+    }
+    let patterns = regex
+        .iter()
+        .cloned()
+        .chain(contains.iter().map(|s| regex::escape(s)));
+    match bytes::RegexSet::new(patterns) {
+        Ok(set) => Ok(Some(set)),
+        Err(e) => Err(format!("invalid body pattern: {}", e)),
+    }
+}
+
+/// 按照 Content-Encoding 解码读取到的实体内容，只用于检查，转发时仍然使用原始的数据
+///
+/// 支持 gzip 和 deflate，complete 为 false 时只解码已经读取的部分，
+/// 解码之后最多保留 limit 个字节，不支持的压缩方式或者数据有误时返回错误
+pub fn decode(
+    encoding: &str,
+    data: &[u8],
+    complete: bool,
+    limit: usize,
+) -> Result<Vec<u8>, String> {
+    let mut data = data.to_vec();
+    // 多个压缩方式按照使用的顺序排列，从最后一个开始解码
+    for coding in encoding.split(',').rev() {
+        let coding = coding.trim().to_ascii_lowercase();
+        let mut decoded = Vec::new();
+        let result = match coding.as_str() {
+            "" | "identity" => continue,
+            "gzip" | "x-gzip" => GzDecoder::new(&data[..])
+                .take(limit as u64)
+                .read_to_end(&mut decoded),
+            // 有些服务端使用没有 zlib 头部的 deflate
+            "deflate" => match ZlibDecoder::new(&data[..])
+                .take(limit as u64)
+                .read_to_end(&mut decoded)
+            {
+                Err(_) if decoded.is_empty() => DeflateDecoder::new(&data[..])
+                    .take(limit as u64)
+                    .read_to_end(&mut decoded),
+                result => result,
+            },
+            _ => return Err(format!("unsupported Content-Encoding `{}`", coding)),
+        };
+        // 没有读取完整的实体内容时，解码到已经读取的数据结束为止
+        if let Err(e) = result {
+            if complete {
+                return Err(format!("decode {} body failed: {}", coding, e));
+            }
+        }
+        data = decoded;
+    }
+    Ok(data)
+}
+
+// Content-Type 中的媒体类型，转换成小写，不包含参数
+fn media_type(request: &http::Request) -> Option<&'static str> {
+    let content_type = request.headers.get("Content-Type")?;
+    let media_type = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    match media_type.as_str() {
+        "application/json" => Some("application/json"),
+        "application/x-www-form-urlencoded" => Some("application/x-www-form-urlencoded"),
+        t if t.ends_with("+json") => Some("application/json"),
+        _ => None,
+    }
+}
+
+// application/json 以及 `+json` 结尾的类型
+fn is_json(request: &http::Request) -> bool {
+    media_type(request) == Some("application/json")
+}
+
+// 解析 application/x-www-form-urlencoded 编码的表单
+fn parse_form(data: &[u8]) -> Vec<(String, String)> {
+    data.split(|&b| b == b'&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, |&b| b == b'=');
+            let key = parts.next().unwrap_or(&[]);
+            let value = parts.next().unwrap_or(&[]);
+            (percent_decode(key), percent_decode(value))
+        })
+        .collect()
+}
+
+// `+` 解码为空格，`%XX` 解码为对应的字节，不合法的编码保持原样
+fn percent_decode(data: &[u8]) -> String {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let mut result = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            b'+' => result.push(b' '),
+            b'%' if i + 2 < data.len() => match (hex(data[i + 1]), hex(data[i + 2])) {
+                (Some(high), Some(low)) => {
+                    result.push(high << 4 | low);
+                    i += 2;
+                }
+                _ => result.push(b'%'),
+            },
+            b => result.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+#[test]
+fn compiled_body_test() {
+    use crate::config::{FormField, JsonField};
+
+    let mut request = http::Request::default();
+    request
+        .headers
+        .insert("Content-Type", "application/json; charset=utf-8");
+    let complete = |data: &'static [u8]| InspectedBody {
+        data,
+        complete: true,
+    };
+
+    let rule = RequestBody {
+        json: vec![JsonField {
+            pointer: "/user/role".to_string(),
+            value: serde_json::json!("admin"),
+        }],
+        ..RequestBody::default()
+    };
+    let body = CompiledBody::new(&rule, 1024).unwrap();
+    assert!(body.is_match(&request, &complete(br#"{"user": {"role": "admin"}}"#)));
+    assert!(!body.is_match(&request, &complete(br#"{"user": {"role": "guest"}}"#)));
+    assert!(!body.is_match(&request, &complete(b"{")));
+    // 没有读取完整时不匹配
+    let partial = InspectedBody {
+        data: br#"{"user": {"role": "admin"}}"#,
+        complete: false,
+    };
+    assert!(!body.is_match(&request, &partial));
+
+    // 表单字段的值解码之后匹配
+    request
+        .headers
+        .insert("Content-Type", "application/x-www-form-urlencoded");
+    let rule = RequestBody {
+        form: vec![FormField {
+            name: "api key".to_string(),
+            value: "^sk-".to_string(),
+        }],
+        ..RequestBody::default()
+    };
+    let body = CompiledBody::new(&rule, 1024).unwrap();
+    assert!(body.is_match(&request, &complete(b"a=1&api+key=sk%2D123")));
+    assert!(!body.is_match(&request, &complete(b"a=sk-1&api+key=pk")));
+    assert_eq!(percent_decode(b"%41%zz%4"), "A%zz%4");
+
+    // 长度和内容同时满足
+    let rule = RequestBody {
+        max_size: Some(4),
+        contains: vec!["secret".to_string()],
+        ..RequestBody::default()
+    };
+    let body = CompiledBody::new(&rule, 16).unwrap();
+    assert!(body.is_match(&request, &complete(b"my secret")));
+    assert!(!body.is_match(&request, &complete(b"public data")));
+    request.body = http::Body::Length(3);
+    assert!(!body.is_match(&request, &complete(b"my secret")));
+    // 没有读取完整的 chunked 实体内容一定超过 max_size
+    request.body = http::Body::Chunked;
+    let partial = InspectedBody {
+        data: b"secret",
+        complete: false,
+    };
+    assert!(body.is_match(&request, &partial));
+
+    assert!(CompiledBody::new(&rule, 4).is_err());
+    assert!(CompiledBody::new(&RequestBody::default(), 16).is_err());
+}
+
+#[test]
+fn decode_test() {
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    let body = b"Traceback (most recent call last)".repeat(100);
+    let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+    gzip.write_all(&body).unwrap();
+    let gzip = gzip.finish().unwrap();
+    let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+    zlib.write_all(&body).unwrap();
+    let zlib = zlib.finish().unwrap();
+    let mut deflate = DeflateEncoder::new(Vec::new(), Compression::default());
+    deflate.write_all(&body).unwrap();
+    let deflate = deflate.finish().unwrap();
+
+    assert_eq!(decode("gzip", &gzip, true, 1 << 20).unwrap(), body);
+    assert_eq!(decode("X-Gzip", &gzip, true, 1 << 20).unwrap(), body);
+    assert_eq!(decode("deflate", &zlib, true, 1 << 20).unwrap(), body);
+    assert_eq!(decode("deflate", &deflate, true, 1 << 20).unwrap(), body);
+    assert_eq!(decode("identity", &body, true, 1 << 20).unwrap(), body);
+    // 解码之后最多保留 limit 个字节
+    assert_eq!(decode("gzip", &gzip, true, 10).unwrap(), b"Traceback ");
+
+    // 只读取了开头的一部分时解码已经读取的部分
+    let partial = decode("gzip", &gzip[..gzip.len() / 2], false, 1 << 20).unwrap();
+    assert!(body.starts_with(&partial));
+    assert!(decode("gzip", &gzip[..gzip.len() / 2], true, 1 << 20).is_err());
+
+    // 先 gzip 再 deflate
+    let mut twice = ZlibEncoder::new(Vec::new(), Compression::default());
+    twice.write_all(&gzip).unwrap();
+    let twice = twice.finish().unwrap();
+    assert_eq!(
+        decode("gzip, deflate", &twice, true, 1 << 20).unwrap(),
+        body
+    );
+
+    assert_eq!(
+        decode("br", &body, true, 1 << 20).unwrap_err(),
+        "unsupported Content-Encoding `br`"
+    );
+    assert!(decode("gzip", b"not gzip", true, 1 << 20).is_err());
+}
@@ -0,0 +1,717 @@
+//! compiled.rs 将配置中的规则编译成便于匹配的形式
+//!
+//! 正则表达式只在加载配置的时候编译一次，有误的规则在加载时就会被拒绝
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use ipnet::IpNet;
+use regex::{bytes, Regex, RegexSet};
+
+use super::acl::{parse_net, Acl};
+use super::block::BlockPage;
+use super::body::{compile_patterns, CompiledBody};
+use super::host::{compile_hosts, HostPattern};
+use super::path::compile_paths;
+use super::{FilterStatus, Identity, Verdict};
+use crate::auth::{JwtVerifier, UserStore};
+use crate::config::{
+    Action, Block, BodyPattern, Config, DenyConfig, Expr, Header, Mode, Request, Response,
+};
+
+/// 编译之后的全部规则
+///
+/// allow 中的规则在前，deny 中的规则在后，匹配时按照顺序第一个匹配的规则生效
+#[derive(Debug)]
+pub struct CompiledRuleSet {
+    pub(super) request: Vec<CompiledRequest>,
+    pub(super) response: Vec<CompiledResponse>,
+    /// 所有规则都不匹配时的动作
+    pub(super) default: Action,
+    // default 的模式，同时是规则没有配置 mode 时的模式
+    mode: Mode,
+    // 规则没有配置拒绝页面时使用
+    block: Option<BlockPage>,
+    acl: Acl,
+    // server.auth 中的用户
+    users: UserStore,
+    // 验证 Bearer token，没有配置 server.auth.jwt 时为 None
+    jwt: Option<JwtVerifier>,
+    // 没有 enforce 模式的规则匹配，使用 default 的次数
+    pub(super) request_default_hits: Counter,
+    pub(super) response_default_hits: Counter,
+}
+
+/// 规则的命中次数，重新加载配置之后名字相同的规则继续计数
+pub(super) type Counter = Arc<AtomicU64>;
+
+/// 一条规则的命中次数，用于输出统计信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleHits {
+    /// `request` 或者 `response`
+    pub kind: &'static str,
+    /// 规则的名字，没有规则匹配时为 `default`
+    pub rule: String,
+    pub action: Action,
+    pub mode: Mode,
+    pub hits: u64,
+}
+
+#[derive(Debug)]
+pub(super) struct CompiledRequest {
+    pub name: String,
+    pub action: Action,
+    pub headers: Vec<Header>,
+    pub methods: Vec<String>,
+    // 没有配置路径时为 None，不做限制
+    pub paths: Option<RegexSet>,
+    // 没有配置主机时为空，不做限制
+    pub hosts: Vec<HostPattern>,
+    pub expr: Option<CompiledExpr>,
+    pub body: Option<CompiledBody>,
+    // 都为空时不做限制
+    pub users: Vec<String>,
+    pub groups: Vec<String>,
+    pub block: Option<BlockPage>,
+    pub mode: Mode,
+    pub hits: Counter,
+}
+
+/// 编译之后的组合条件，与 `config::Expr` 一一对应
+#[derive(Debug)]
+pub(super) enum CompiledExpr {
+    All(Vec<CompiledExpr>),
+    Any(Vec<CompiledExpr>),
+    Not(Box<CompiledExpr>),
+    Method(String),
+    Path(Regex),
+    Header(Header),
+    Host(Regex),
+    Client(IpNet),
+    Claim(String, Regex),
+}
+
+#[derive(Debug)]
+pub(super) struct CompiledResponse {
+    pub name: String,
+    pub action: Action,
+    pub headers: Vec<Header>,
+    // 状态码的范围，包含边界，为空时不做限制
+    pub status: Vec<(u16, u16)>,
+    // Content-Length 的范围，包含边界
+    pub content_length: Option<(u64, u64)>,
+    // contains 转义之后和 regex 编译在一起
+    pub body: Option<bytes::RegexSet>,
+    pub block: Option<BlockPage>,
+    pub mode: Mode,
+    pub hits: Counter,
+}
+
+impl CompiledRuleSet {
+    /// 编译配置中的规则，规则有误时返回的错误中包含规则的名字
+    pub fn new(config: &Config) -> Result<CompiledRuleSet, String> {
+        let mut rules = CompiledRuleSet {
+            request: Vec::new(),
+            response: Vec::new(),
+            default: config.policy.default,
+            mode: config.policy.mode,
+            block: match &config.policy.block {
+                Some(block) => {
+                    Some(BlockPage::new(block).map_err(|e| format!("policy.block: {}", e))?)
+                }
+                None => None,
+            },
+            acl: Acl::new(&config.server.acl)?,
+            users: UserStore::new(&config.server.auth)
+                .map_err(|e| format!("server.auth: {}", e))?,
+            jwt: match &config.server.auth.jwt {
+                Some(jwt) if config.server.auth.enable => {
+                    Some(JwtVerifier::new(jwt).map_err(|e| format!("server.auth.jwt: {}", e))?)
+                }
+                _ => None,
+            },
+            request_default_hits: Counter::default(),
+            response_default_hits: Counter::default(),
+        };
+        let limit = config.server.max_inspect_body_size;
+        rules.add("allow", Action::Allow, &config.allow, limit)?;
+        rules.add("deny", Action::Deny, &config.deny, limit)?;
+        Ok(rules)
+    }
+
+    // 编译一部分规则，section 为 allow 或者 deny，limit 为读取实体内容的最大字节数
+    fn add(
+        &mut self,
+        section: &str,
+        action: Action,
+        config: &DenyConfig,
+        limit: usize,
+    ) -> Result<(), String> {
+        for rule in config.request.iter() {
+            match CompiledRequest::new(action, rule, limit) {
+                Ok(r) => self.request.push(CompiledRequest {
+                    mode: rule.mode.unwrap_or(self.mode),
+                    ..r
+                }),
+                Err(e) => return Err(format!("{}.request `{}`: {}", section, rule.name, e)),
+            }
+        }
+        for rule in config.response.iter() {
+            match CompiledResponse::new(action, rule) {
+                Ok(r) => self.response.push(CompiledResponse {
+                    mode: rule.mode.unwrap_or(self.mode),
+                    ..r
+                }),
+                Err(e) => return Err(format!("{}.response `{}`: {}", section, rule.name, e)),
+            }
+        }
+        Ok(())
+    }
+
+    // 匹配的规则决定的结果，规则没有配置拒绝页面时使用 policy 中的页面
+    //
+    // matched 为规则中配置的条件，audit 为在这条规则之前匹配的 audit 模式的规则
+    pub(super) fn verdict<'a>(
+        &'a self,
+        action: Action,
+        rule: &'a str,
+        matched: Vec<&'static str>,
+        block: Option<&'a BlockPage>,
+        audit: Option<(&'a str, FilterStatus)>,
+    ) -> Verdict<'a> {
+        Verdict {
+            status: action.into(),
+            rule: Some(rule),
+            matched,
+            block: block.or(self.block.as_ref()),
+            audit,
+        }
+    }
+
+    // 没有 enforce 模式的规则匹配时的结果
+    //
+    // default 为 audit 模式并且动作为 deny 时，只记录会被拒绝，仍然转发
+    pub(super) fn default_verdict<'a>(
+        &'a self,
+        audit: Option<(&'a str, FilterStatus)>,
+    ) -> Verdict<'a> {
+        match self.mode {
+            Mode::Enforce => Verdict {
+                status: self.default.into(),
+                rule: None,
+                matched: Vec::new(),
+                block: self.block.as_ref(),
+                audit,
+            },
+            Mode::Audit => Verdict {
+                status: FilterStatus::Forward,
+                rule: None,
+                matched: Vec::new(),
+                block: None,
+                audit: match self.default {
+                    Action::Deny => audit.or(Some(("default", FilterStatus::Reject))),
+                    Action::Allow => audit,
+                },
+            },
+        }
+    }
+
+    /// 所有的拒绝页面，用于重新加载时比较模板文件的内容是否发生了变化
+    pub fn block_pages(&self) -> Vec<(String, &BlockPage)> {
+        let mut pages = Vec::new();
+        if let Some(page) = &self.block {
+            pages.push(("policy".to_string(), page));
+        }
+        for rule in self.request.iter() {
+            if let Some(page) = &rule.block {
+                pages.push((format!("request `{}`", rule.name), page));
+            }
+        }
+        for rule in self.response.iter() {
+            if let Some(page) = &rule.block {
+                pages.push((format!("response `{}`", rule.name), page));
+            }
+        }
+        pages
+    }
+
+    /// 所有规则的命中次数，按照匹配的顺序，request 和 response 各自最后是 default
+    pub fn hits(&self) -> Vec<RuleHits> {
+        let load = |counter: &Counter| counter.load(Ordering::Relaxed);
+        let default = |kind, counter| RuleHits {
+            kind,
+            rule: "default".to_string(),
+            action: self.default,
+            mode: self.mode,
+            hits: load(counter),
+        };
+
+        let mut result = Vec::new();
+        for rule in self.request.iter() {
+            result.push(RuleHits {
+                kind: "request",
+                rule: rule.name.clone(),
+                action: rule.action,
+                mode: rule.mode,
+                hits: load(&rule.hits),
+            });
+        }
+        result.push(default("request", &self.request_default_hits));
+        for rule in self.response.iter() {
+            result.push(RuleHits {
+                kind: "response",
+                rule: rule.name.clone(),
+                action: rule.action,
+                mode: rule.mode,
+                hits: load(&rule.hits),
+            });
+        }
+        result.push(default("response", &self.response_default_hits));
+        result
+    }
+
+    /// 重新加载配置时使用之前的计数器，名字相同的规则继续计数
+    ///
+    /// 使用之前配置的连接与新的连接共享同一个计数器
+    pub fn inherit_hits(&mut self, old: &CompiledRuleSet) {
+        for rule in self.request.iter_mut() {
+            if let Some(r) = old.request.iter().find(|r| r.name == rule.name) {
+                rule.hits = r.hits.clone();
+            }
+        }
+        for rule in self.response.iter_mut() {
+            if let Some(r) = old.response.iter().find(|r| r.name == rule.name) {
+                rule.hits = r.hits.clone();
+            }
+        }
+        self.request_default_hits = old.request_default_hits.clone();
+        self.response_default_hits = old.response_default_hits.clone();
+    }
+
+    /// 检查客户端地址是否可以使用代理
+    pub fn check_client(&self, addr: IpAddr) -> FilterStatus {
+        self.acl.check(addr)
+    }
+
+    /// 验证代理鉴权的用户名和密码
+    pub async fn authenticate(&self, username: &str, password: &str) -> bool {
+        self.users.verify(username, password).await
+    }
+
+    /// 用户所在的组
+    pub fn groups(&self, username: &str) -> &[String] {
+        self.users.groups(username)
+    }
+
+    /// 代理鉴权的用户，digest 鉴权时使用
+    pub fn users(&self) -> &UserStore {
+        &self.users
+    }
+
+    /// 验证 Bearer token，返回 token 对应的用户
+    pub fn verify_token(&self, token: &str) -> Result<Identity, String> {
+        match &self.jwt {
+            Some(jwt) => jwt.verify(token),
+            None => Err("bearer token is not enabled".to_string()),
+        }
+    }
+
+    /// 用户与之前的规则是否不同，用于重新加载时检查 htpasswd 文件的内容是否发生了变化
+    pub fn users_changed(&self, old: &CompiledRuleSet) -> bool {
+        self.users != old.users
+    }
+
+    /// 是否有需要检查请求实体内容的规则
+    pub fn inspects_request_body(&self) -> bool {
+        self.request.iter().any(|rule| rule.body.is_some())
+    }
+
+    /// 是否有需要检查响应实体内容的规则
+    pub fn inspects_response_body(&self) -> bool {
+        self.response.iter().any(|rule| rule.body.is_some())
+    }
+}
+
+impl CompiledRequest {
+    pub fn new(action: Action, rule: &Request, limit: usize) -> Result<CompiledRequest, String> {
+        let line = &rule.rule.line;
+        if line.methods.iter().any(|m| m.is_empty()) {
+            return Err("empty method".to_string());
+        }
+        validate_headers(&rule.rule.headers)?;
+
+        let paths = if line.path.is_empty() {
+            None
+        } else {
+            Some(compile_paths(&line.path)?)
+        };
+        let hosts = compile_hosts(&rule.rule.hosts)?;
+        let expr = match &rule.rule.expr {
+            Some(expr) => Some(compile_expr(expr)?),
+            None => None,
+        };
+        let body = match &rule.rule.body {
+            Some(body) => Some(CompiledBody::new(body, limit)?),
+            None => None,
+        };
+        if rule.rule.users.iter().any(|u| u.is_empty()) {
+            return Err("empty user".to_string());
+        }
+        if rule.rule.groups.iter().any(|g| g.is_empty()) {
+            return Err("empty group".to_string());
+        }
+        let block = compile_block(action, &rule.block)?;
+
+        Ok(CompiledRequest {
+            name: rule.name.clone(),
+            action,
+            headers: rule.rule.headers.clone(),
+            methods: line.methods.clone(),
+            paths,
+            hosts,
+            expr,
+            body,
+            users: rule.rule.users.clone(),
+            groups: rule.rule.groups.clone(),
+            block,
+            mode: rule.mode.unwrap_or_default(),
+            hits: Counter::default(),
+        })
+    }
+
+    /// 规则中配置的条件，规则匹配时这些条件全部满足
+    pub fn conditions(&self) -> Vec<&'static str> {
+        let mut conditions = Vec::new();
+        if !self.methods.is_empty() {
+            conditions.push("method");
+        }
+        if self.paths.is_some() {
+            conditions.push("path");
+        }
+        if !self.hosts.is_empty() {
+            conditions.push("host");
+        }
+        if !self.headers.is_empty() {
+            conditions.push("headers");
+        }
+        if !self.users.is_empty() || !self.groups.is_empty() {
+            conditions.push("user");
+        }
+        if self.expr.is_some() {
+            conditions.push("expr");
+        }
+        if self.body.is_some() {
+            conditions.push("body");
+        }
+        conditions
+    }
+
+    /// 记录一次命中
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl CompiledResponse {
+    pub fn new(action: Action, rule: &Response) -> Result<CompiledResponse, String> {
+        validate_headers(&rule.rule.headers)?;
+        let status = rule
+            .rule
+            .status
+            .iter()
+            .map(|s| parse_status(s))
+            .collect::<Result<Vec<_>, _>>()?;
+        let content_length = match &rule.rule.content_length {
+            Some(range) => {
+                let min = range.min.unwrap_or(0);
+                let max = range.max.unwrap_or(u64::MAX);
+                if min > max {
+                    return Err(format!("invalid content_length range {}-{}", min, max));
+                }
+                Some((min, max))
+            }
+            None => None,
+        };
+        let body = match &rule.rule.body {
+            Some(body) => Some(compile_body(body)?),
+            None => None,
+        };
+        let block = compile_block(action, &rule.block)?;
+
+        Ok(CompiledResponse {
+            name: rule.name.clone(),
+            action,
+            headers: rule.rule.headers.clone(),
+            status,
+            content_length,
+            body,
+            block,
+            mode: rule.mode.unwrap_or_default(),
+            hits: Counter::default(),
+        })
+    }
+
+    /// 规则中配置的条件，规则匹配时这些条件全部满足
+    pub fn conditions(&self) -> Vec<&'static str> {
+        let mut conditions = Vec::new();
+        if !self.status.is_empty() {
+            conditions.push("status");
+        }
+        if self.content_length.is_some() {
+            conditions.push("content_length");
+        }
+        if !self.headers.is_empty() {
+            conditions.push("headers");
+        }
+        if self.body.is_some() {
+            conditions.push("body");
+        }
+        conditions
+    }
+
+    /// 记录一次命中
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// 拒绝页面只用于 deny 中的规则
+fn compile_block(action: Action, block: &Option<Block>) -> Result<Option<BlockPage>, String> {
+    match block {
+        Some(_) if action == Action::Allow => Err("block is only used by deny rules".to_string()),
+        Some(block) => BlockPage::new(block).map(Some),
+        None => Ok(None),
+    }
+}
+
+// 解析状态码，支持 `404`、`5xx` 以及 `500-599`
+fn parse_status(status: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("invalid status `{}`", status);
+    let code = |s: &str| match s.trim().parse::<u16>() {
+        Ok(code) if (100..=999).contains(&code) => Ok(code),
+        _ => Err(invalid()),
+    };
+
+    let status = status.trim();
+    if let Some((min, max)) = status.split_once('-') {
+        let (min, max) = (code(min)?, code(max)?);
+        if min > max {
+            return Err(invalid());
+        }
+        return Ok((min, max));
+    }
+    let lower = status.to_ascii_lowercase();
+    if lower.len() == 3 && lower.ends_with("xx") {
+        let class = code(&format!("{}00", &lower[..1]))?;
+        return Ok((class, class + 99));
+    }
+    let code = code(status)?;
+    Ok((code, code))
+}
+
+fn compile_body(body: &BodyPattern) -> Result<bytes::RegexSet, String> {
+    match compile_patterns(&body.regex, &body.contains)? {
+        Some(patterns) => Ok(patterns),
+        None => Err("empty body pattern".to_string()),
+    }
+}
+
+pub(super) fn compile_expr(expr: &Expr) -> Result<CompiledExpr, String> {
+    let compiled = match expr {
+        Expr::All(exprs) => CompiledExpr::All(compile_exprs(exprs)?),
+        Expr::Any(exprs) => CompiledExpr::Any(compile_exprs(exprs)?),
+        Expr::Not(expr) => CompiledExpr::Not(Box::new(compile_expr(expr)?)),
+        Expr::Method(method) => {
+            if method.is_empty() {
+                return Err("empty method".to_string());
+            }
+            CompiledExpr::Method(method.clone())
+        }
+        Expr::Path(pattern) => CompiledExpr::Path(compile_regex(pattern)?),
+        Expr::Host(pattern) => CompiledExpr::Host(compile_regex(pattern)?),
+        Expr::Header(header) => {
+            validate_headers(std::slice::from_ref(header))?;
+            CompiledExpr::Header(header.clone())
+        }
+        Expr::Client(net) => match parse_net(net) {
+            Ok(net) => CompiledExpr::Client(net),
+            Err(_) => return Err(format!("invalid client address `{}`", net)),
+        },
+        Expr::Claim(claim) => {
+            if claim.name.is_empty() {
+                return Err("empty claim name".to_string());
+            }
+            CompiledExpr::Claim(claim.name.clone(), compile_regex(&claim.value)?)
+        }
+    };
+    Ok(compiled)
+}
+
+fn compile_exprs(exprs: &[Expr]) -> Result<Vec<CompiledExpr>, String> {
+    exprs.iter().map(compile_expr).collect()
+}
+
+fn compile_regex(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| format!("invalid regex `{}`: {}", pattern, e))
+}
+
+// 头部规则的名字不能为空
+fn validate_headers(headers: &[Header]) -> Result<(), String> {
+    if headers.iter().any(|h| h.key.is_empty()) {
+        return Err("empty header key".to_string());
+    }
+    Ok(())
+}
+
+#[test]
+fn compiled_rule_set_test() {
+    use super::path_rule;
+    use crate::config::RequestDeny;
+
+    let mut config = Config::default();
+    config
+        .allow
+        .request
+        .push(path_rule("ops", "^/admin/ops", None));
+    config
+        .deny
+        .request
+        .push(path_rule("admin", "^/admin", None));
+    let rules = CompiledRuleSet::new(&config).unwrap();
+    let names: Vec<_> = rules.request.iter().map(|r| r.name.as_str()).collect();
+    assert_eq!(names, vec!["ops", "admin"]);
+    assert_eq!(rules.request[0].action, Action::Allow);
+
+    // 有误的正则表达式在编译时被拒绝，错误中包含规则的名字
+    config
+        .deny
+        .request
+        .push(path_rule("broken", "/login(", None));
+    let err = CompiledRuleSet::new(&config).unwrap_err();
+    assert!(err.contains("deny.request `broken`"));
+    assert!(err.contains("/login("));
+
+    let mut config = Config::default();
+    config.deny.request.push(Request {
+        name: "nested".to_string(),
+        rule: RequestDeny {
+            expr: Some(Expr::Any(vec![Expr::Not(Box::new(Expr::Host(
+                "[".to_string(),
+            )))])),
+            ..RequestDeny::default()
+        },
+        block: None,
+        mode: None,
+    });
+    assert!(CompiledRuleSet::new(&config)
+        .unwrap_err()
+        .contains("deny.request `nested`"));
+}
+
+#[test]
+fn compiled_response_test() {
+    use crate::config::{LengthRange, ResponseDeny};
+
+    assert_eq!(parse_status("404"), Ok((404, 404)));
+    assert_eq!(parse_status("5xx"), Ok((500, 599)));
+    assert_eq!(parse_status("5XX"), Ok((500, 599)));
+    assert_eq!(parse_status("400-403"), Ok((400, 403)));
+    for status in ["", "abc", "xx", "9999", "500-400", "50x"].iter() {
+        assert!(parse_status(status).is_err(), "{}", status);
+    }
+
+    let mut config = Config::default();
+    config.deny.response.push(Response {
+        name: "broken".to_string(),
+        rule: ResponseDeny {
+            content_length: Some(LengthRange {
+                min: Some(10),
+                max: Some(1),
+            }),
+            ..ResponseDeny::default()
+        },
+        block: None,
+        mode: None,
+    });
+    assert!(CompiledRuleSet::new(&config)
+        .unwrap_err()
+        .contains("deny.response `broken`"));
+
+    // contains 中的内容按照字面匹配
+    let body = compile_body(&BodyPattern {
+        regex: vec![],
+        contains: vec!["a.b(".to_string()],
+    })
+    .unwrap();
+    assert!(body.is_match(b"x a.b( y"));
+    assert!(!body.is_match(b"x axb( y"));
+    assert!(compile_body(&BodyPattern::default()).is_err());
+}
+
+#[test]
+fn rule_hits_test() {
+    use super::path_rule as rule;
+    use super::request::evaluate;
+    use super::Context;
+    use crate::http;
+
+    let check = |rules: &CompiledRuleSet, path: &str| {
+        let mut request = http::Request::default();
+        request.path = path.to_string();
+        let context = Context {
+            request: &request,
+            client: None,
+            user: None,
+            body: None,
+        };
+        evaluate(rules, &context).status
+    };
+    let hits = |rules: &CompiledRuleSet| -> Vec<(String, u64)> {
+        rules
+            .hits()
+            .into_iter()
+            .map(|h| (format!("{} {}", h.kind, h.rule), h.hits))
+            .collect()
+    };
+
+    let mut config = Config::default();
+    config
+        .deny
+        .request
+        .push(rule("new", "^/admin", Some(Mode::Audit)));
+    config
+        .deny
+        .request
+        .push(rule("admin", "^/admin/users", None));
+    let rules = CompiledRuleSet::new(&config).unwrap();
+    assert_eq!(check(&rules, "/admin/users"), FilterStatus::Reject);
+    assert_eq!(check(&rules, "/admin"), FilterStatus::Forward);
+    assert_eq!(check(&rules, "/index"), FilterStatus::Forward);
+    // audit 模式的规则同样计数
+    assert_eq!(
+        hits(&rules),
+        vec![
+            ("request new".to_string(), 2),
+            ("request admin".to_string(), 1),
+            ("request default".to_string(), 2),
+            ("response default".to_string(), 0),
+        ]
+    );
+
+    // 重新加载之后名字相同的规则继续计数，并且与之前的规则共享计数器
+    config.deny.request.remove(0);
+    config.deny.request.push(rule("login", "^/login", None));
+    let mut reloaded = CompiledRuleSet::new(&config).unwrap();
+    reloaded.inherit_hits(&rules);
+    assert_eq!(check(&rules, "/admin/users"), FilterStatus::Reject);
+    assert_eq!(
+        hits(&reloaded),
+        vec![
+            ("request admin".to_string(), 2),
+            ("request login".to_string(), 0),
+            ("request default".to_string(), 2),
+            ("response default".to_string(), 0),
+        ]
+    );
+}
@@ -1,31 +1,39 @@
-use std::collections::HashMap;
+use crate::http::HeaderMap;
 
 /// 头部过滤器
 ///
 /// 对头部的信息进行过滤，比如声明 key: value
 /// 那么如果头部包含 key，并且对应的头部值**包含** value
-/// 那么会被过滤掉
+/// 那么会被过滤掉，同名的头部有多个时任意一个包含即可
+///
+/// 头部的名字不区分大小写，`host` 和 `Host` 是同一个头部
 // 过滤头部，通用方法
-pub fn filter_header(headers: &HashMap<String, String>, key: &str, value: &str) -> bool {
-    match headers.get(key) {
-        Some(v) => {
-            if v.contains(value) {
-                return true;
-            }
-        }
-        None => {}
-    }
-    false
+pub fn filter_header(headers: &HeaderMap, key: &str, value: &str) -> bool {
+    headers.get_all(key).any(|v| v.contains(value))
 }
 
 #[test]
 fn filter_test() {
-    let mut headers = HashMap::new();
-    headers.insert("Content-Type".to_string(), "application/json".to_string());
-    headers.insert("Authorization".to_string(), "Bearer hello".to_string());
-    headers.insert("Host".to_string(), "www.baidu.com".to_string());
+    let mut headers = HeaderMap::new();
+    headers.append("Content-Type", "application/json");
+    headers.append("Authorization", "Bearer hello");
+    headers.append("Host", "www.baidu.com");
+    headers.append("Set-Cookie", "a=1");
+    headers.append("Set-Cookie", "b=2");
 
     assert!(filter_header(&headers, "Content-Type", "json"));
 
     assert!(!filter_header(&headers, "Host", "www.google.com"));
+
+    // 重复的头部都会被检查
+    assert!(filter_header(&headers, "Set-Cookie", "b=2"));
+
+    // 名字不区分大小写，值仍然区分大小写
+    assert!(filter_header(&headers, "content-type", "json"));
+    assert!(filter_header(&headers, "HOST", "baidu"));
+    assert!(!filter_header(&headers, "Host", "BAIDU"));
+
+    let mut lower = HeaderMap::new();
+    lower.append("content-type", "application/json");
+    assert!(filter_header(&lower, "Content-Type", "json"));
 }
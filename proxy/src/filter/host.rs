@@ -0,0 +1,119 @@
+//! 目的主机过滤
+//!
+//! 支持以下几种写法，主机名不区分大小写：
+//!
+//! - `example.com` 完全相同
+//! - `*.example.com` 通配符，`*` 匹配任意非空的字符
+//! - `.example.com` 后缀，匹配 example.com 本身以及所有子域名
+//! - `~^ads[0-9]+\.` 以 `~` 开头的为正则表达式
+
+use crate::http;
+use regex::Regex;
+
+/// 编译之后的主机规则
+#[derive(Debug)]
+pub enum HostPattern {
+    Exact(String),
+    Suffix(String),
+    Regex(Regex),
+}
+
+impl HostPattern {
+    pub fn is_match(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        match self {
+            HostPattern::Exact(name) => host == *name,
+            HostPattern::Suffix(suffix) => host == suffix[1..] || host.ends_with(suffix.as_str()),
+            HostPattern::Regex(reg) => reg.is_match(&host),
+        }
+    }
+}
+
+/// 编译主机规则，出错时返回对应的规则
+pub fn compile_hosts(hosts: &[String]) -> Result<Vec<HostPattern>, String> {
+    hosts.iter().map(|host| compile_host(host)).collect()
+}
+
+fn compile_host(host: &str) -> Result<HostPattern, String> {
+    if let Some(pattern) = host.strip_prefix('~') {
+        return match Regex::new(pattern) {
+            Ok(reg) => Ok(HostPattern::Regex(reg)),
+            Err(e) => Err(format!("invalid host regex `{}`: {}", pattern, e)),
+        };
+    }
+
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if host.is_empty() || host == "." {
+        return Err("empty host".to_string());
+    }
+    if host.contains('*') {
+        // 通配符转换成正则表达式
+        let pattern = host
+            .split('*')
+            .map(regex::escape)
+            .collect::<Vec<_>>()
+            .join(".+");
+        return match Regex::new(&format!("^{}$", pattern)) {
+            Ok(reg) => Ok(HostPattern::Regex(reg)),
+            Err(e) => Err(format!("invalid host `{}`: {}", host, e)),
+        };
+    }
+    if host.starts_with('.') {
+        return Ok(HostPattern::Suffix(host));
+    }
+    Ok(HostPattern::Exact(host))
+}
+
+// 如果请求的目的主机满足 hosts 中的任意一个，则应该被过滤
+//
+// CONNECT 请求使用请求行中的地址，其他请求使用 Host 头部
+pub fn filter_request_host(hosts: &[HostPattern], request: &http::Request) -> bool {
+    match request.host() {
+        Some(host) => hosts.iter().any(|pattern| pattern.is_match(host)),
+        None => false,
+    }
+}
+
+#[test]
+fn filter_request_host_test() {
+    let hosts = vec![
+        "Example.com".to_string(),
+        "*.ads.net".to_string(),
+        ".tracker.org".to_string(),
+        r"~^cdn[0-9]+\.".to_string(),
+    ];
+    let hosts = compile_hosts(&hosts).unwrap();
+
+    let mut request = http::Request::default();
+    let mut check = |host: &str| {
+        request.headers.insert("Host", host);
+        filter_request_host(&hosts, &request)
+    };
+
+    // 完全相同，不区分大小写，不包含端口
+    assert!(check("example.com:8080"));
+    assert!(check("EXAMPLE.COM."));
+    assert!(!check("www.example.com"));
+
+    // 通配符不匹配域名本身
+    assert!(check("a.ads.net"));
+    assert!(check("a.b.ads.net"));
+    assert!(!check("ads.net"));
+
+    // 后缀匹配域名本身以及子域名
+    assert!(check("tracker.org"));
+    assert!(check("x.tracker.org"));
+    assert!(!check("badtracker.org"));
+
+    assert!(check("cdn12.example.net"));
+    assert!(!check("cdn.example.net"));
+
+    // CONNECT 请求使用请求行中的地址
+    request.headers = http::HeaderMap::new();
+    request.method = http::Method::CONNECT;
+    request.path = "x.tracker.org:443".to_string();
+    assert!(filter_request_host(&hosts, &request));
+
+    assert!(compile_hosts(&["~(".to_string()]).is_err());
+    assert!(compile_hosts(&["".to_string()]).is_err());
+}
@@ -1,7 +1,18 @@
-use crate::config::Config;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::config::Action;
 use crate::http;
 
+pub use block::BlockPage;
+pub use compiled::{CompiledRuleSet, RuleHits};
+
+mod acl;
+mod block;
+mod body;
+mod compiled;
 mod header;
+mod host;
 mod method;
 mod path;
 pub mod request;
@@ -16,8 +27,103 @@ pub enum FilterStatus {
     Reject,
 }
 
-/// 对客户端的请求进行过滤
-pub type FilterRequest = fn(config: &Config, request: &http::Request) -> FilterStatus;
+impl From<Action> for FilterStatus {
+    fn from(action: Action) -> Self {
+        match action {
+            Action::Allow => FilterStatus::Forward,
+            Action::Deny => FilterStatus::Reject,
+        }
+    }
+}
+
+/// 过滤的结果
+#[derive(Debug)]
+pub struct Verdict<'a> {
+    pub status: FilterStatus,
+    /// 匹配的规则的名字，没有规则匹配时为 None
+    pub rule: Option<&'a str>,
+    /// 匹配的规则中配置的条件，例如 `method`、`path`，没有规则匹配时为空
+    pub matched: Vec<&'static str>,
+    /// 拒绝时返回给客户端的页面，没有配置时为 None
+    pub block: Option<&'a BlockPage>,
+    /// 第一个匹配的 audit 模式的规则（default 为 audit 模式时为 `default`）
+    /// 以及它生效时的结果，只在它会决定结果时记录
+    pub audit: Option<(&'a str, FilterStatus)>,
+}
+
+impl Verdict<'_> {
+    /// 做出决定的原因，用于输出日志
+    pub fn reason(&self) -> String {
+        match self.rule {
+            Some(rule) if self.matched.is_empty() => format!("rule `{}` matched any", rule),
+            Some(rule) => format!("rule `{}` matched {}", rule, self.matched.join(", ")),
+            None => "no rule matched, policy.default".to_string(),
+        }
+    }
+}
+
+/// 过滤请求时可以使用的信息
+pub struct Context<'a> {
+    pub request: &'a http::Request,
+    /// 客户端的地址
+    pub client: Option<IpAddr>,
+    /// 通过鉴权的用户，没有开启鉴权时为 None
+    pub user: Option<&'a Identity>,
+    /// 读取到的实体内容，没有读取时为 None
+    pub body: Option<InspectedBody<'a>>,
+}
+
+/// 通过鉴权的用户以及用户所在的组
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Identity {
+    pub name: String,
+    pub groups: Vec<String>,
+    /// 使用 JWT 鉴权时 token 中的 claim，数组展开为多个值，对象不保存
+    pub claims: HashMap<String, Vec<String>>,
+}
+
+/// 读取到的实体内容，chunked 编码已经被解码
+#[derive(Debug, Clone, Copy)]
+pub struct InspectedBody<'a> {
+    pub data: &'a [u8],
+    /// 是否读取了完整的实体内容
+    pub complete: bool,
+}
+
+// 测试中使用的请求规则，只匹配 path
+#[cfg(test)]
+fn path_rule(name: &str, path: &str, mode: Option<crate::config::Mode>) -> crate::config::Request {
+    use crate::config::{Request, RequestDeny, RequestLine};
 
-/// 对服务端返回的响应进行过滤
-pub type FilterResponse = fn(config: &Config, response: &http::Response) -> FilterStatus;
+    Request {
+        name: name.to_string(),
+        rule: RequestDeny {
+            line: RequestLine {
+                methods: vec![],
+                path: vec![path.to_string()],
+            },
+            ..RequestDeny::default()
+        },
+        block: None,
+        mode,
+    }
+}
+
+// 测试中使用的响应规则，只匹配 Content-Type
+#[cfg(test)]
+fn content_type_rule(name: &str, value: &str) -> crate::config::Response {
+    use crate::config::{Header, Response, ResponseDeny};
+
+    Response {
+        name: name.to_string(),
+        rule: ResponseDeny {
+            headers: vec![Header {
+                key: "Content-Type".to_string(),
+                value: value.to_string(),
+            }],
+            ..ResponseDeny::default()
+        },
+        block: None,
+        mode: None,
+    }
+}
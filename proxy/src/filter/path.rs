@@ -1,24 +1,24 @@
 //! 请求路径过滤
 
 use crate::http;
-use log::error;
-use regex::Regex;
+use regex::{Regex, RegexSet};
 
-// 如果请求路径中包含在 paths 中，则应该被过滤
-pub fn filter_request_path(paths: &Vec<String>, request: &http::Request) -> bool {
+/// 编译请求路径的正则表达式
+///
+/// 多个表达式编译成一个 RegexSet，匹配时只需要扫描一遍路径
+pub fn compile_paths(paths: &[String]) -> Result<RegexSet, String> {
+    // 逐个检查，便于指出哪一个表达式有误
     for path in paths.iter() {
-        let reg = match Regex::new(path) {
-            Ok(e) => e,
-            Err(e) => {
-                error!("Regex error: {}", &e);
-                return false;
-            }
-        };
-        if reg.is_match(&request.path) {
-            return true;
+        if let Err(e) = Regex::new(path) {
+            return Err(format!("invalid path regex `{}`: {}", path, e));
         }
     }
-    false
+    RegexSet::new(paths).map_err(|e| e.to_string())
+}
+
+// 如果请求路径匹配 paths 中的任意一个，则应该被过滤
+pub fn filter_request_path(paths: &RegexSet, request: &http::Request) -> bool {
+    paths.is_match(&request.path)
 }
 
 #[test]
@@ -27,16 +27,21 @@ fn filter_request_path_test() {
     let mut request = http::Request::default();
 
     path.push("/login".to_string());
-    assert!(!filter_request_path(&path, &request));
+    let set = compile_paths(&path).unwrap();
+    assert!(!filter_request_path(&set, &request));
 
     request.path = "/login".to_string();
-    assert!(filter_request_path(&path, &request));
+    assert!(filter_request_path(&set, &request));
 
     path.push("/admin/.*".to_string());
-    assert!(filter_request_path(&path, &request));
+    let set = compile_paths(&path).unwrap();
+    assert!(filter_request_path(&set, &request));
     request.path = "/admin/user_id".to_string();
-    assert!(filter_request_path(&path, &request));
+    assert!(filter_request_path(&set, &request));
 
     request.path = "/admin_suffix".to_string();
-    assert!(!filter_request_path(&path, &request));
+    assert!(!filter_request_path(&set, &request));
+
+    path.push("/admin(".to_string());
+    assert!(compile_paths(&path).unwrap_err().contains("/admin("));
 }
@@ -1,54 +1,161 @@
+use super::acl::normalize;
+use super::compiled::{CompiledExpr, CompiledRequest};
 use super::header::filter_header;
+use super::host::filter_request_host;
 use super::method::filter_request_method;
 use super::path::filter_request_path;
+use super::{CompiledRuleSet, Context, Verdict};
+use crate::config::{Header, Mode};
+use log::info;
+use std::sync::atomic::Ordering;
+
+#[cfg(test)]
 use super::FilterStatus;
-use crate::config::{Header, Request};
+#[cfg(test)]
+use crate::config::{Action, Request};
+#[cfg(test)]
 use crate::http;
 
-// 过滤请求中的头部信息
-pub fn filter_request(request_config: &Vec<Request>, request: &http::Request) -> FilterStatus {
-    // // 是否被过滤掉
-    // let mut flag = true;
-
-    // 多个匹配规则 {name, rule}
-    for Request { name, rule } in request_config.iter() {
-        let mut flag = true;
-
-        // 比较头部
-        // 配置中的文件应该全部被 request 包含
-        for Header { key, value } in rule.headers.iter() {
-            // 如果配置中的头部没有被包含
-            if !filter_header(&request.headers, key, value) {
-                flag = false;
-                break;
-            }
+/// 按照配置中的策略检查请求，返回匹配的规则以及拒绝时使用的页面
+///
+/// 依次匹配 allow 和 deny 中的规则，第一个匹配的规则决定是否转发，
+/// 都不匹配时使用 policy.default；
+/// audit 模式的规则不影响结果，第一个匹配的 audit 规则记录在 Verdict 中，
+/// 匹配的规则（包括 audit 模式的规则）都会增加命中次数
+pub fn evaluate<'a>(rules: &'a CompiledRuleSet, context: &Context) -> Verdict<'a> {
+    let mut audit = None;
+    for rule in rules.request.iter() {
+        if !match_request(rule, context) {
+            continue;
         }
-        if !flag {
+        rule.hit();
+        if rule.mode == Mode::Audit {
+            audit = audit.or(Some((rule.name.as_str(), rule.action.into())));
             continue;
         }
+        info!(
+            "request matched rule `{}`, action: {:?}",
+            rule.name, rule.action
+        );
+        return rules.verdict(
+            rule.action,
+            &rule.name,
+            rule.conditions(),
+            rule.block.as_ref(),
+            audit,
+        );
+    }
+    rules.request_default_hits.fetch_add(1, Ordering::Relaxed);
+    rules.default_verdict(audit)
+}
 
-        // 比较方法
-        if !filter_request_method(&rule.line.methods, request) {
-            continue;
+/// 是否需要读取实体内容才能决定如何处理请求
+///
+/// 按照顺序第一个除实体内容之外的条件都匹配的规则配置了 body 时才需要读取，
+/// audit 模式的规则不影响结果，之后的规则仍然需要检查
+pub fn needs_body(rules: &CompiledRuleSet, context: &Context) -> bool {
+    for rule in rules.request.iter() {
+        if match_head(rule, context) {
+            if rule.body.is_some() {
+                return true;
+            }
+            if rule.mode == Mode::Enforce {
+                return false;
+            }
         }
+    }
+    false
+}
 
-        // 比较路径
-        if !filter_request_path(&rule.line.path, request) {
-            continue;
+// 请求是否满足规则，配置了 body 的规则还需要满足实体内容的条件
+fn match_request(rule: &CompiledRequest, context: &Context) -> bool {
+    if !match_head(rule, context) {
+        return false;
+    }
+    match (&rule.body, &context.body) {
+        (None, _) => true,
+        (Some(compiled), Some(body)) => compiled.is_match(context.request, body),
+        (Some(_), None) => false,
+    }
+}
+
+// 头部全部满足，方法和路径各自满足其一，并且满足组合条件
+//
+// 没有配置方法或者路径时不做限制
+fn match_head(rule: &CompiledRequest, context: &Context) -> bool {
+    let request = context.request;
+    // 比较头部
+    // 配置中的文件应该全部被 request 包含
+    for Header { key, value } in rule.headers.iter() {
+        // 如果配置中的头部没有被包含
+        if !filter_header(&request.headers, key, value) {
+            return false;
         }
+    }
+
+    // 比较方法和路径
+    if !rule.methods.is_empty() && !filter_request_method(&rule.methods, request) {
+        return false;
+    }
+    if let Some(paths) = &rule.paths {
+        if !filter_request_path(paths, request) {
+            return false;
+        }
+    }
+    if !rule.hosts.is_empty() && !filter_request_host(&rule.hosts, request) {
+        return false;
+    }
+    if (!rule.users.is_empty() || !rule.groups.is_empty()) && !match_user(rule, context) {
+        return false;
+    }
 
-        println!("Rejected by rule: {}", name);
-        return FilterStatus::Reject;
+    match &rule.expr {
+        Some(expr) => match_expr(expr, context),
+        None => true,
+    }
+}
+
+// 用户在 users 中或者在 groups 中的某个组中，没有通过鉴权的请求不匹配
+fn match_user(rule: &CompiledRequest, context: &Context) -> bool {
+    match context.user {
+        Some(user) => {
+            rule.users.contains(&user.name) || user.groups.iter().any(|g| rule.groups.contains(g))
+        }
+        None => false,
+    }
+}
+
+/// 计算组合条件，`all` 为空时满足，`any` 为空时不满足
+pub(super) fn match_expr(expr: &CompiledExpr, context: &Context) -> bool {
+    let request = context.request;
+    match expr {
+        CompiledExpr::All(exprs) => exprs.iter().all(|e| match_expr(e, context)),
+        CompiledExpr::Any(exprs) => exprs.iter().any(|e| match_expr(e, context)),
+        CompiledExpr::Not(e) => !match_expr(e, context),
+        CompiledExpr::Method(method) => request.method.to_string().eq_ignore_ascii_case(method),
+        CompiledExpr::Path(reg) => reg.is_match(&request.path),
+        CompiledExpr::Header(Header { key, value }) => filter_header(&request.headers, key, value),
+        CompiledExpr::Host(reg) => match request.host() {
+            Some(host) => reg.is_match(host),
+            None => false,
+        },
+        CompiledExpr::Client(net) => match context.client {
+            Some(client) => net.contains(&normalize(client)),
+            None => false,
+        },
+        CompiledExpr::Claim(name, reg) => match context.user.and_then(|u| u.claims.get(name)) {
+            Some(values) => values.iter().any(|v| reg.is_match(v)),
+            None => false,
+        },
     }
-    FilterStatus::Forward
 }
 
 #[test]
-fn filter_request_test() {
-    use crate::config::{Request, RequestDeny, RequestLine};
-    use std::collections::HashMap;
+fn evaluate_request_test() {
+    use crate::config::{Config, Request, RequestDeny, RequestLine};
+    use crate::http::HeaderMap;
 
-    let mut request_config = Vec::new();
+    let mut config = Config::default();
 
     let rule = RequestDeny {
         headers: vec![
@@ -65,44 +172,389 @@ fn filter_request_test() {
             methods: vec!["POST".to_string(), "GET".to_string()],
             path: vec!["/login".to_string()],
         },
+        hosts: vec![],
+        expr: None,
+        body: None,
+        users: vec![],
+        groups: vec![],
     };
 
-    request_config.push(Request {
+    config.deny.request.push(Request {
         name: "test".to_string(),
-        rule: rule,
+        rule,
+        block: None,
+        mode: None,
     });
+    let rules = CompiledRuleSet::new(&config).unwrap();
+    let check = |request: &http::Request| {
+        let context = Context {
+            request,
+            client: None,
+            user: None,
+            body: None,
+        };
+        evaluate(&rules, &context)
+    };
 
-    let mut h = HashMap::new();
-    h.insert("Content-Type".to_string(), "pdf".to_string());
+    let mut h = HeaderMap::new();
+    h.insert("Content-Type", "pdf");
 
     let mut request = http::Request::default();
     request.headers = h.clone();
 
     // 因为仅仅包含一个，没有全部包含
+    assert_eq!(check(&request).status, FilterStatus::Forward);
+
+    h.insert("Host", "www.baidu.com");
+    request.headers = h;
+
+    // 头部全部包含，但是方法和路径不对
+    assert_eq!(check(&request).status, FilterStatus::Forward);
+
+    // 头部全部包含, 方法正确，和路径不对
+    request.method = http::Method::POST;
+    assert_eq!(check(&request).status, FilterStatus::Forward);
+
+    request.path = "/login".to_string();
+    let verdict = check(&request);
+    assert_eq!(verdict.status, FilterStatus::Reject);
+    assert_eq!(verdict.rule, Some("test"));
+    assert_eq!(verdict.matched, vec!["method", "path", "headers"]);
     assert_eq!(
-        filter_request(&request_config, &request),
-        FilterStatus::Forward
+        verdict.reason(),
+        "rule `test` matched method, path, headers"
     );
 
-    h.insert("Host".to_string(), "www.baidu.com".to_string());
+    // 客户端使用小写的头部名字同样会被过滤
+    let mut h = HeaderMap::new();
+    h.insert("content-type", "pdf");
+    h.insert("host", "www.baidu.com");
     request.headers = h;
+    assert_eq!(check(&request).status, FilterStatus::Reject);
 
-    // 头部全部包含，但是方法和路径不对
+    let mut h = HeaderMap::new();
+    h.insert("CONTENT-TYPE", "pdf");
+    request.headers = h;
+    assert_eq!(check(&request).status, FilterStatus::Forward);
+}
+
+#[test]
+fn check_request_test() {
+    use super::path_rule;
+    use crate::config::{Config, Policy};
+    use crate::http::HeaderMap;
+
+    fn check(config: &Config, request: &http::Request) -> FilterStatus {
+        let rules = CompiledRuleSet::new(config).unwrap();
+        let context = Context {
+            request,
+            client: None,
+            user: None,
+            body: None,
+        };
+        evaluate(&rules, &context).status
+    }
+
+    fn rule(name: &str, path: &str, headers: Vec<Header>) -> Request {
+        let mut rule = path_rule(name, path, None);
+        rule.rule.line.methods = vec!["GET".to_string()];
+        rule.rule.headers = headers;
+        rule
+    }
+
+    // /admin 只允许带有 X-Ops 头部的请求访问
+    let mut config = Config::default();
+    config.allow.request.push(rule(
+        "ops",
+        "^/admin",
+        vec![Header {
+            key: "X-Ops".to_string(),
+            value: "yes".to_string(),
+        }],
+    ));
+    config.deny.request.push(rule("admin", "^/admin", vec![]));
+
+    let mut request = http::Request::default();
+    request.method = http::Method::GET;
+    request.path = "/admin/users".to_string();
+    assert_eq!(check(&config, &request), FilterStatus::Reject);
+
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Ops", "yes");
+    request.headers = headers;
+    assert_eq!(check(&config, &request), FilterStatus::Forward);
+
+    // 没有规则匹配时使用默认的动作
+    request.path = "/index".to_string();
+    assert_eq!(check(&config, &request), FilterStatus::Forward);
+    config.policy = Policy {
+        default: Action::Deny,
+        block: None,
+        mode: Mode::Enforce,
+    };
+    assert_eq!(check(&config, &request), FilterStatus::Reject);
+
+    // allow 规则优先于 deny 规则匹配
+    config.allow.request.push(rule("index", "^/index", vec![]));
+    config.deny.request.insert(0, rule("all", "^/", vec![]));
+    assert_eq!(check(&config, &request), FilterStatus::Forward);
+    request.path = "/other".to_string();
+    assert_eq!(check(&config, &request), FilterStatus::Reject);
+}
+
+#[test]
+fn evaluate_audit_test() {
+    use super::path_rule as rule;
+    use crate::config::Config;
+
+    let mut request = http::Request::default();
+    let mut evaluate = |config: &Config, path: &str| {
+        request.path = path.to_string();
+        let rules = CompiledRuleSet::new(config).unwrap();
+        let context = Context {
+            request: &request,
+            client: None,
+            user: None,
+            body: None,
+        };
+        let verdict = evaluate(&rules, &context);
+        (
+            verdict.status,
+            verdict.rule.map(String::from),
+            verdict
+                .audit
+                .map(|(rule, status)| (rule.to_string(), status)),
+        )
+    };
+
+    let mut config = Config::default();
+    config
+        .deny
+        .request
+        .push(rule("new", "^/admin", Some(Mode::Audit)));
+    config.deny.request.push(rule("old", "^/admin/users", None));
+
+    // audit 规则只记录，继续匹配后面的规则
+    assert_eq!(
+        evaluate(&config, "/admin/index"),
+        (
+            FilterStatus::Forward,
+            None,
+            Some(("new".to_string(), FilterStatus::Reject))
+        )
+    );
     assert_eq!(
-        filter_request(&request_config, &request),
-        FilterStatus::Forward
+        evaluate(&config, "/admin/users"),
+        (
+            FilterStatus::Reject,
+            Some("old".to_string()),
+            Some(("new".to_string(), FilterStatus::Reject))
+        )
+    );
+    assert_eq!(
+        evaluate(&config, "/index"),
+        (FilterStatus::Forward, None, None)
     );
 
-    // 头部全部包含, 方法正确，和路径不对
-    request.method = http::Method::POST;
+    // 全局 audit 模式，规则中配置的 mode 优先
+    config.policy.mode = Mode::Audit;
+    config.policy.default = Action::Deny;
+    config.deny.request[0].mode = Some(Mode::Enforce);
+    assert_eq!(
+        evaluate(&config, "/admin/index"),
+        (FilterStatus::Reject, Some("new".to_string()), None)
+    );
     assert_eq!(
-        filter_request(&request_config, &request),
-        FilterStatus::Forward
+        evaluate(&config, "/index"),
+        (
+            FilterStatus::Forward,
+            None,
+            Some(("default".to_string(), FilterStatus::Reject))
+        )
     );
+}
+
+#[test]
+fn match_expr_test() {
+    use super::compiled::compile_expr;
+    use crate::config::{Expr, RequestDeny};
+    use crate::http::HeaderMap;
+
+    // /admin 下的请求只允许运维人员从本机访问
+    let expr: Expr = serde_yaml::from_str(
+        r#"
+all:
+  - path: ^/admin
+  - not:
+      all:
+        - header: { key: X-Ops, value: "true" }
+        - any:
+            - client: 127.0.0.1
+            - client: "::1"
+            - client: 192.168.0.0/16
+"#,
+    )
+    .unwrap();
+    let expr = compile_expr(&expr).unwrap();
+
+    let local = Some("127.0.0.1".parse().unwrap());
+    let mut request = http::Request::default();
+    request.path = "/admin".to_string();
+    let context = Context {
+        request: &request,
+        client: local,
+        user: None,
+        body: None,
+    };
+    assert!(match_expr(&expr, &context));
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-ops", "true");
+    request.headers = headers;
+    let mut context = Context {
+        request: &request,
+        client: local,
+        user: None,
+        body: None,
+    };
+    assert!(!match_expr(&expr, &context));
+
+    context.client = Some("10.0.0.1".parse().unwrap());
+    assert!(match_expr(&expr, &context));
+
+    // 客户端地址支持 CIDR，IPv4 映射的 IPv6 地址同样可以匹配
+    context.client = Some("192.168.3.4".parse().unwrap());
+    assert!(!match_expr(&expr, &context));
+    context.client = Some("::ffff:192.168.3.4".parse().unwrap());
+    assert!(!match_expr(&expr, &context));
+
+    // 方法不区分大小写，主机名不包含端口
+    request.method = http::Method::POST;
+    request.headers.insert("Host", "api.example.com:8080");
+    let context = Context {
+        request: &request,
+        client: None,
+        user: None,
+        body: None,
+    };
+    let expr = compile_expr(&Expr::All(vec![
+        Expr::Method("post".to_string()),
+        Expr::Host(r"^api\.example\.com$".to_string()),
+    ]))
+    .unwrap();
+    assert!(match_expr(&expr, &context));
+    assert!(match_expr(&CompiledExpr::All(vec![]), &context));
+    assert!(!match_expr(&CompiledExpr::Any(vec![]), &context));
+
+    // 只有头部条件的规则
+    let rule = Request {
+        name: "host".to_string(),
+        rule: RequestDeny {
+            headers: vec![Header {
+                key: "Host".to_string(),
+                value: "example".to_string(),
+            }],
+            ..RequestDeny::default()
+        },
+        block: None,
+        mode: None,
+    };
+    let rule = CompiledRequest::new(Action::Deny, &rule, usize::MAX).unwrap();
+    assert!(match_request(&rule, &context));
+}
+
+#[test]
+fn check_request_user_test() {
+    use crate::config::{Config, RequestDeny, RequestLine};
+    use crate::filter::Identity;
+
+    let mut config = Config::default();
+    config.deny.request.push(Request {
+        name: "contractors".to_string(),
+        rule: RequestDeny {
+            line: RequestLine {
+                methods: vec![],
+                path: vec!["^/admin".to_string()],
+            },
+            users: vec!["eve".to_string()],
+            groups: vec!["contractors".to_string()],
+            ..RequestDeny::default()
+        },
+        block: None,
+        mode: None,
+    });
+    let rules = CompiledRuleSet::new(&config).unwrap();
+    let mut request = http::Request::default();
+    request.path = "/admin/users".to_string();
+    let check = |user: Option<&Identity>| {
+        let context = Context {
+            request: &request,
+            client: None,
+            user,
+            body: None,
+        };
+        evaluate(&rules, &context).status
+    };
+    let user = |name: &str, groups: &[&str]| Identity {
+        name: name.to_string(),
+        groups: groups.iter().map(|g| g.to_string()).collect(),
+        ..Identity::default()
+    };
 
-    request.path = "/login".to_string();
     assert_eq!(
-        filter_request(&request_config, &request),
+        check(Some(&user("bob", &["dev", "contractors"]))),
         FilterStatus::Reject
     );
+    assert_eq!(check(Some(&user("eve", &[]))), FilterStatus::Reject);
+    assert_eq!(check(Some(&user("alice", &["ops"]))), FilterStatus::Forward);
+    // 没有通过鉴权的请求不匹配
+    assert_eq!(check(None), FilterStatus::Forward);
+}
+
+#[test]
+fn check_request_claim_test() {
+    use crate::config::{Claim, Config, Expr, RequestDeny};
+    use crate::filter::Identity;
+
+    // 没有 write 权限的 token 不能修改
+    let mut config = Config::default();
+    config.deny.request.push(Request {
+        name: "read_only".to_string(),
+        rule: RequestDeny {
+            expr: Some(Expr::All(vec![
+                Expr::Method("POST".to_string()),
+                Expr::Not(Box::new(Expr::Claim(Claim {
+                    name: "scope".to_string(),
+                    value: "^write$".to_string(),
+                }))),
+            ])),
+            ..RequestDeny::default()
+        },
+        block: None,
+        mode: None,
+    });
+    let rules = CompiledRuleSet::new(&config).unwrap();
+    let mut request = http::Request::default();
+    request.method = http::Method::POST;
+    let check = |scopes: &[&str]| {
+        let mut user = Identity {
+            name: "ci".to_string(),
+            ..Identity::default()
+        };
+        user.claims.insert(
+            "scope".to_string(),
+            scopes.iter().map(|s| s.to_string()).collect(),
+        );
+        let context = Context {
+            request: &request,
+            client: None,
+            user: Some(&user),
+            body: None,
+        };
+        evaluate(&rules, &context).status
+    };
+
+    assert_eq!(check(&["read", "write"]), FilterStatus::Forward);
+    assert_eq!(check(&["read"]), FilterStatus::Reject);
+    assert_eq!(check(&[]), FilterStatus::Reject);
 }
@@ -1,35 +1,138 @@
+use super::body::decode;
+use super::compiled::CompiledResponse;
 use super::header::filter_header;
-use super::FilterStatus;
-use crate::config::{Header, Response};
+use super::{CompiledRuleSet, Verdict};
+use crate::config::{Header, Mode};
 use crate::http;
+use log::info;
+use std::borrow::Cow;
+use std::sync::atomic::Ordering;
 
-// 过滤响应
-pub fn filter_response(response_config: &Vec<Response>, response: &http::Response) -> FilterStatus {
-    for Response { name, rule } in response_config.iter() {
-        let mut flag = true;
-        // 如果响应的头部没有全部包含配置中的头部
-        for Header { key, value } in rule.headers.iter() {
-            // 如果配置中的头部没有被包含
-            if !filter_header(&response.headers, key, value) {
-                flag = false;
-                break;
-            }
+#[cfg(test)]
+use super::FilterStatus;
+#[cfg(test)]
+use crate::config::Response;
+
+/// 按照配置中的策略检查响应，规则的顺序与请求相同，返回匹配的规则以及拒绝时使用的页面
+///
+/// body 为读取到的实体内容（可能只是开头的一部分），
+/// 为 None 时配置了 body 的规则都不匹配
+pub fn evaluate<'a>(
+    rules: &'a CompiledRuleSet,
+    response: &http::Response,
+    body: Option<&[u8]>,
+) -> Verdict<'a> {
+    let mut audit = None;
+    for rule in rules.response.iter() {
+        if !match_response(rule, response, body) {
+            continue;
         }
-        if !flag {
+        rule.hit();
+        if rule.mode == Mode::Audit {
+            audit = audit.or(Some((rule.name.as_str(), rule.action.into())));
             continue;
         }
-        println!("Rejected by rule: {}", name);
-        return FilterStatus::Reject;
+        info!(
+            "response matched rule `{}`, action: {:?}",
+            rule.name, rule.action
+        );
+        return rules.verdict(
+            rule.action,
+            &rule.name,
+            rule.conditions(),
+            rule.block.as_ref(),
+            audit,
+        );
+    }
+    rules.response_default_hits.fetch_add(1, Ordering::Relaxed);
+    rules.default_verdict(audit)
+}
+
+/// 实体内容无法检查时（例如使用了不支持的压缩方式）使用 policy.default
+pub fn uninspectable(rules: &CompiledRuleSet) -> Verdict<'_> {
+    rules.response_default_hits.fetch_add(1, Ordering::Relaxed);
+    rules.default_verdict(None)
+}
+
+/// 按照响应的 Content-Encoding 解码读取到的实体内容，没有压缩时直接使用读取到的数据
+///
+/// complete 表示是否读取了完整的实体内容，无法解码时返回错误
+pub fn decode_body<'a>(
+    response: &http::Response,
+    data: &'a [u8],
+    complete: bool,
+    limit: usize,
+) -> Result<Cow<'a, [u8]>, String> {
+    match response.headers.get("Content-Encoding") {
+        Some(encoding) => decode(encoding, data, complete, limit).map(Cow::Owned),
+        None => Ok(Cow::Borrowed(data)),
+    }
+}
+
+/// 是否需要读取实体内容才能决定如何处理响应
+///
+/// 按照顺序第一个头部等条件匹配的规则配置了 body 时才需要读取，
+/// 不需要实体内容就能匹配的规则在前面时不读取，audit 模式的规则除外
+pub fn needs_body(rules: &CompiledRuleSet, response: &http::Response) -> bool {
+    for rule in rules.response.iter() {
+        if match_head(rule, response) {
+            if rule.body.is_some() {
+                return true;
+            }
+            if rule.mode == Mode::Enforce {
+                return false;
+            }
+        }
     }
-    FilterStatus::Forward
+    false
+}
+
+// 状态码、Content-Length 以及头部全部满足，并且实体内容满足 body 中的其一
+fn match_response(rule: &CompiledResponse, response: &http::Response, body: Option<&[u8]>) -> bool {
+    if !match_head(rule, response) {
+        return false;
+    }
+    match (&rule.body, body) {
+        (None, _) => true,
+        (Some(patterns), Some(body)) => patterns.is_match(body),
+        (Some(_), None) => false,
+    }
+}
+
+// 不需要实体内容的部分
+fn match_head(rule: &CompiledResponse, response: &http::Response) -> bool {
+    if !rule.status.is_empty()
+        && !rule
+            .status
+            .iter()
+            .any(|&(min, max)| (min..=max).contains(&response.code))
+    {
+        return false;
+    }
+    if let Some((min, max)) = rule.content_length {
+        match content_length(response) {
+            Some(length) if (min..=max).contains(&length) => {}
+            _ => return false,
+        }
+    }
+    rule.headers
+        .iter()
+        .all(|Header { key, value }| filter_header(&response.headers, key, value))
+}
+
+fn content_length(response: &http::Response) -> Option<u64> {
+    response
+        .headers
+        .get("Content-Length")
+        .and_then(|length| length.trim().parse().ok())
 }
 
 #[test]
-fn filter_response_test() {
-    use crate::config::ResponseDeny;
-    use std::collections::HashMap;
+fn evaluate_response_test() {
+    use crate::config::{Config, ResponseDeny};
+    use crate::http::HeaderMap;
 
-    let mut response_config = Vec::new();
+    let mut config = Config::default();
 
     let rule = ResponseDeny {
         headers: vec![
@@ -42,31 +145,148 @@ fn filter_response_test() {
                 value: "www.baidu.com".to_string(),
             },
         ],
+        ..ResponseDeny::default()
     };
 
-    response_config.push(Response {
+    config.deny.response.push(Response {
         name: "test".to_string(),
-        rule: rule,
+        rule,
+        block: None,
+        mode: None,
     });
+    let rules = CompiledRuleSet::new(&config).unwrap();
 
-    let mut h = HashMap::new();
-    h.insert("Content-Type".to_string(), "pdf".to_string());
+    let mut h = HeaderMap::new();
+    h.insert("Content-Type", "pdf");
 
     let mut response = http::Response::default();
     response.headers = h.clone();
 
     // 因为仅仅包含一个，没有全部包含
     assert_eq!(
-        filter_response(&response_config, &response),
+        evaluate(&rules, &response, None).status,
         FilterStatus::Forward
     );
 
-    h.insert("Host".to_string(), "www.baidu.com".to_string());
+    h.insert("Host", "www.baidu.com");
     response.headers = h;
 
     // 全部包含
+    let verdict = evaluate(&rules, &response, None);
+    assert_eq!(verdict.status, FilterStatus::Reject);
+    assert_eq!(verdict.rule, Some("test"));
+    assert_eq!(verdict.matched, vec!["headers"]);
+
+    // 服务端使用小写的头部名字同样会被过滤
+    let mut h = HeaderMap::new();
+    h.insert("content-type", "application/pdf");
+    h.insert("host", "www.baidu.com");
+    response.headers = h;
+    assert_eq!(
+        evaluate(&rules, &response, None).status,
+        FilterStatus::Reject
+    );
+}
+
+#[test]
+fn check_response_test() {
+    use super::content_type_rule as rule;
+    use crate::config::Config;
+    use crate::http::HeaderMap;
+
+    // 禁止 pdf，但是允许 pdf 的预览图
+    let mut config = Config::default();
+    config.deny.response.push(rule("pdf", "pdf"));
+    config.allow.response.push(rule("preview", "pdf+preview"));
+
+    let mut response = http::Response::default();
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/pdf");
+    response.headers = headers.clone();
+    assert_eq!(
+        evaluate(&CompiledRuleSet::new(&config).unwrap(), &response, None).status,
+        FilterStatus::Reject
+    );
+
+    headers.insert("Content-Type", "application/pdf+preview");
+    response.headers = headers;
+    assert_eq!(
+        evaluate(&CompiledRuleSet::new(&config).unwrap(), &response, None).status,
+        FilterStatus::Forward
+    );
+}
+
+#[test]
+fn check_response_body_test() {
+    use crate::config::{BodyPattern, Config, LengthRange, ResponseDeny};
+
+    let mut config = Config::default();
+    config.deny.response.push(Response {
+        name: "stack_trace".to_string(),
+        rule: ResponseDeny {
+            status: vec!["5xx".to_string()],
+            body: Some(BodyPattern {
+                regex: vec![r"at [\w.$]+\(\w+\.java:[0-9]+\)".to_string()],
+                contains: vec!["Traceback".to_string()],
+            }),
+            ..ResponseDeny::default()
+        },
+        block: None,
+        mode: None,
+    });
+    config.deny.response.push(Response {
+        name: "large".to_string(),
+        rule: ResponseDeny {
+            content_length: Some(LengthRange {
+                min: Some(1000),
+                max: None,
+            }),
+            ..ResponseDeny::default()
+        },
+        block: None,
+        mode: None,
+    });
+    let rules = CompiledRuleSet::new(&config).unwrap();
+    assert!(rules.inspects_response_body());
+
+    let mut response = http::Response::default();
+    response.code = 500;
+    assert!(needs_body(&rules, &response));
+    let check =
+        |response: &http::Response, body: &[u8]| evaluate(&rules, response, Some(body)).status;
+    assert_eq!(
+        check(&response, b"at com.example.Foo.bar(Foo.java:42)"),
+        FilterStatus::Reject
+    );
+    assert_eq!(
+        check(&response, b"Traceback (most recent call last)"),
+        FilterStatus::Reject
+    );
+    assert_eq!(check(&response, b"internal error"), FilterStatus::Forward);
+    // 没有读取实体内容时不匹配
+    assert_eq!(
+        evaluate(&rules, &response, None).status,
+        FilterStatus::Forward
+    );
+
+    // 状态码不满足时不需要读取实体内容
+    response.code = 200;
+    assert!(!needs_body(&rules, &response));
+    assert_eq!(check(&response, b"Traceback"), FilterStatus::Forward);
+
+    // 没有 Content-Length 头部时不匹配长度
+    assert_eq!(
+        evaluate(&rules, &response, None).status,
+        FilterStatus::Forward
+    );
+    response.headers.insert("Content-Length", "999");
+    assert_eq!(
+        evaluate(&rules, &response, None).status,
+        FilterStatus::Forward
+    );
+    response.headers.insert("Content-Length", "1000");
     assert_eq!(
-        filter_response(&response_config, &response),
+        evaluate(&rules, &response, None).status,
         FilterStatus::Reject
     );
 }
@@ -0,0 +1,1067 @@
+//! handler.rs 负责处理客户端的连接
+
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use log::{error, info};
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use crate::auth::digest::{self, DigestError};
+use crate::auth::{self, FailureTracker, NonceStore};
+use crate::config::{Config, Scheme};
+use crate::filter::{request, response};
+use crate::filter::{CompiledRuleSet, Context, FilterStatus, Identity, InspectedBody, Verdict};
+use crate::http::Method;
+use crate::pool::ConnectionPool;
+use crate::{http, tunnel, utils};
+
+/// 所有连接共享的状态
+pub struct State {
+    // 当前使用的配置以及根据配置编译好的规则，重新加载时一起替换
+    current: RwLock<(Arc<Config>, Arc<CompiledRuleSet>)>,
+    /// 到目的服务器的连接池，只使用启动时的配置
+    pub upstream: ConnectionPool,
+    /// digest 鉴权下发的 nonce，重新加载配置时保留
+    pub nonces: NonceStore,
+    /// 鉴权失败的记录，重新加载配置时保留
+    pub failures: FailureTracker,
+}
+
+impl State {
+    /// 创建共享状态，配置中的规则有误时返回错误
+    pub fn new(config: Arc<Config>) -> Result<State, String> {
+        let rules = CompiledRuleSet::new(&config)?;
+        let upstream = ConnectionPool::new(
+            config.server.upstream.max_idle_per_host,
+            Duration::from_secs(config.server.upstream.idle_timeout),
+        );
+        Ok(State {
+            current: RwLock::new((config, Arc::new(rules))),
+            upstream,
+            nonces: NonceStore::default(),
+            failures: FailureTracker::default(),
+        })
+    }
+
+    /// 获取当前配置以及对应规则的快照
+    pub fn snapshot(&self) -> (Arc<Config>, Arc<CompiledRuleSet>) {
+        match self.current.read() {
+            Ok(current) => current.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    /// 替换配置以及对应的规则，返回之前的配置
+    ///
+    /// 已经取得快照的连接继续使用之前的配置，新的连接使用新的配置
+    pub fn swap(&self, config: Arc<Config>, rules: CompiledRuleSet) -> Arc<Config> {
+        let mut current = match self.current.write() {
+            Ok(current) => current,
+            Err(e) => e.into_inner(),
+        };
+        std::mem::replace(&mut *current, (config, Arc::new(rules))).0
+    }
+}
+
+/// 处理 HTTP 连接
+///
+/// 同一个连接上可以有多个请求，客户端要求关闭连接、出现错误
+/// 或者空闲超过 keep_alive_timeout 时关闭连接，
+/// 整个连接都使用建立连接时的配置
+pub async fn handle_stream(stream: TcpStream, state: Arc<State>) {
+    let (cfg, rules) = state.snapshot();
+    let timeout = match cfg.server.keep_alive_timeout {
+        0 => None,
+        t => Some(Duration::from_secs(t)),
+    };
+
+    let client = stream.peer_addr().ok().map(|addr| addr.ip());
+    let (reader, mut writer) = stream.into_split();
+    // 读取客户端数据使用的缓冲，实体内容以及后续的请求会从这里继续读取
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        // 等待下一个请求
+        let ready = match timeout {
+            Some(t) => match time::timeout(t, reader.fill_buf()).await {
+                Ok(res) => res.map(|buf| buf.is_empty()),
+                Err(_) => {
+                    info!("close idle connection");
+                    return;
+                }
+            },
+            None => reader.fill_buf().await.map(|buf| buf.is_empty()),
+        };
+        match ready {
+            // 客户端关闭了连接
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(e) => {
+                error!("read stream failed: {}", e);
+                return;
+            }
+        }
+
+        // 读取请求行和头部，解析协议
+        let req = match timeout {
+            Some(t) => match time::timeout(t, http::parse_request(&mut reader)).await {
+                Ok(res) => res,
+                Err(_) => Err("read request header timeout"),
+            },
+            None => http::parse_request(&mut reader).await,
+        };
+        let req = match req {
+            Ok(req) => req,
+            Err(err) => {
+                error!("parser request failed: {}", err);
+                http::bad_request(&mut writer).await;
+                return;
+            }
+        };
+
+        if !handle_request(&state, &cfg, &rules, client, &mut writer, &mut reader, req).await {
+            return;
+        }
+    }
+}
+
+// 处理一个 HTTP 请求，返回是否可以继续复用客户端连接
+async fn handle_request(
+    state: &State,
+    cfg: &Config,
+    rules: &CompiledRuleSet,
+    client_addr: Option<IpAddr>,
+    stream: &mut OwnedWriteHalf,
+    reader: &mut BufReader<OwnedReadHalf>,
+    mut req: http::Request,
+) -> bool {
+    // 找到host，CONNECT 请求使用请求行中的地址，与过滤时检查的地址相同，
+    // 不能使用可能与之不同的 Host 头部
+    let host = match req.headers.get("Host") {
+        _ if req.method == Method::CONNECT => req.path.clone(),
+        Some(s) => s.to_string(),
+        None => {
+            error!("No host specified: {:?}", req);
+            return false;
+        }
+    };
+
+    let mut username = String::new();
+    // 使用 JWT 鉴权的用户
+    let mut token_user = None;
+    if cfg.server.auth.enable {
+        // 被锁定的客户端直接拒绝，不验证密码
+        let mut result = match state.failures.locked(client_addr, None) {
+            Some(wait) => Err(AuthError::Locked(wait)),
+            None => authenticate(state, cfg, rules, &req).await,
+        };
+        if let Err(AuthError::Failed(user, reason)) = &result {
+            state.failures.failure(
+                &cfg.server.auth.lockout,
+                rules.users(),
+                client_addr,
+                user.as_deref(),
+                reason,
+            );
+            // 这次失败之后被锁定时直接返回 429
+            if let Some(wait) = state.failures.locked(client_addr, user.as_deref()) {
+                result = Err(AuthError::Locked(wait));
+            }
+        }
+        match result {
+            Ok((name, user)) => {
+                state.failures.success(client_addr, &name);
+                username = name;
+                token_user = user;
+            }
+            Err(AuthError::Locked(wait)) => {
+                // 不足一秒的部分向上取整
+                let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                info!(
+                    target: "security",
+                    "reject locked client {}, retry after {}s",
+                    client_addr.map_or("-".to_string(), |c| c.to_string()),
+                    retry_after
+                );
+                http::too_many_requests(stream, retry_after).await;
+                return false;
+            }
+            Err(e) => {
+                // 要求输入用户名、密码
+                let stale = matches!(e, AuthError::Stale);
+                let challenges = auth::challenges(&cfg.server.auth, &state.nonces, stale);
+                http::proxy_auth(stream, &challenges).await;
+                return false;
+            }
+        }
+    }
+    // 通过鉴权的用户，规则可以只对某些用户或者组生效
+    let user = if cfg.server.auth.enable {
+        token_user.or_else(|| {
+            Some(Identity {
+                name: username.clone(),
+                groups: rules.groups(&username).to_vec(),
+                ..Identity::default()
+            })
+        })
+    } else {
+        None
+    };
+    let host = http::with_port(&host, 80);
+
+    // 需要检查实体内容时先读取开头的一部分，超过限制的部分不检查
+    let mut request_body = None;
+    let head = Context {
+        request: &req,
+        client: client_addr,
+        user: user.as_ref(),
+        body: None,
+    };
+    if request::needs_body(rules, &head) {
+        // 客户端等待 100 Continue 之后才会发送实体内容
+        if req
+            .headers
+            .get("Expect")
+            .is_some_and(|e| e.eq_ignore_ascii_case("100-continue"))
+        {
+            http::http_continue(stream).await;
+        }
+        let mut decoder = http::BodyDecoder::new(req.body);
+        match decoder
+            .read_to_limit(reader, cfg.server.max_inspect_body_size)
+            .await
+        {
+            Ok(data) => request_body = Some((decoder, data)),
+            Err(e) => {
+                error!("read request body failed: {}", e);
+                return false;
+            }
+        }
+    }
+
+    // 过滤请求，CONNECT 请求同样需要过滤，被拒绝时不会连接目的服务器
+    let context = Context {
+        request: &req,
+        client: client_addr,
+        user: user.as_ref(),
+        body: request_body.as_ref().map(|(decoder, data)| InspectedBody {
+            data,
+            complete: decoder.is_finished(),
+        }),
+    };
+    let verdict = request::evaluate(rules, &context);
+    log_audit("request", &verdict, &req);
+    if verdict.status == FilterStatus::Reject {
+        let request_id = utils::request_id();
+        reject(stream, &verdict, &req, client_addr, &request_id).await;
+        info!(
+            "[{}] reject Request, {}: {:?}",
+            request_id,
+            verdict.reason(),
+            req.string()
+        );
+        return false;
+    }
+
+    // https 进行 tunnel
+    if req.method == Method::CONNECT {
+        let mut client = match connect(&host).await {
+            Some(stream) => stream,
+            None => {
+                // 连接到目的服务器失败
+                error!("Connect to server failed");
+                return false;
+            }
+        };
+
+        info!("{} visit {}", username, req.path());
+        http::http_status_ok(stream).await;
+
+        // 客户端缓冲中已经读取的数据会首先被转发
+        let (mut server_reader, mut server_writer) = client.split();
+        let transferred = tunnel::relay(
+            reader,
+            stream,
+            &mut server_reader,
+            &mut server_writer,
+            tunnel_idle_timeout(cfg),
+        )
+        .await;
+        if let Some(e) = transferred.error {
+            error!("tunnel to {} failed: {}", host, e);
+        }
+        info!(
+            "tunnel to {} closed, sent {} bytes, received {} bytes",
+            host, transferred.sent, transferred.received
+        );
+        return false;
+    }
+
+    // 需要在修改头部之前判断客户端是否希望保持连接以及升级协议
+    let keep_alive = req.keep_alive();
+    let upgrade = req.upgrade();
+
+    // 逐跳头部不转发给服务端，启用连接池时要求服务端保持连接
+    req.headers.remove("Proxy-Connection");
+    // 代理的鉴权信息不能发送给目的服务器
+    req.headers.remove("Proxy-Authorization");
+    // 需要检查响应的实体内容时，要求服务端不压缩实体内容，仍然压缩时解码之后检查
+    if rules.inspects_response_body() {
+        req.headers.remove("Accept-Encoding");
+    }
+    if let Some((decoder, data)) = &request_body {
+        // 已经回复过 100 Continue
+        req.headers.remove("Expect");
+        // 实体内容已经全部读取时使用 Content-Length 重新发送
+        if decoder.is_finished() && req.body != http::Body::Empty {
+            req.headers.remove("Transfer-Encoding");
+            req.headers
+                .insert("Content-Length", &data.len().to_string());
+            req.body = http::Body::Length(data.len() as u64);
+        }
+    }
+    // 升级协议时保留 Upgrade，收到 101 之后转为隧道，否则服务端不能返回 101
+    let connection = if upgrade {
+        "upgrade"
+    } else if state.upstream.enabled() {
+        "keep-alive"
+    } else {
+        "close"
+    };
+    if !upgrade {
+        req.headers.remove("Upgrade");
+    }
+    req.headers.insert("Connection", connection);
+
+    // 实体内容已经全部读取时，复用的连接失效之后可以使用新的连接重新发送
+    let replayable = match &request_body {
+        Some((decoder, _)) => decoder.is_finished(),
+        None => req.body == http::Body::Empty,
+    };
+    // 优先使用连接池中的空闲连接
+    let mut pooled = state.upstream.get(&host);
+    let (mut client, mut res) = loop {
+        let reused = pooled.is_some();
+        let server = match pooled.take() {
+            Some(stream) => stream,
+            None => match connect(&host).await {
+                Some(stream) => stream,
+                None => {
+                    // 连接到目的服务器失败
+                    error!("Connect to server failed");
+                    http::bad_gateway(stream).await;
+                    return false;
+                }
+            },
+        };
+        match exchange(server, &mut req, request_body.as_mut(), reader, stream).await {
+            Ok(result) => break result,
+            // 空闲的连接可能已经被服务端关闭，只使用新的连接重试一次
+            Err(UpstreamError::NoResponse(e)) if reused && replayable => {
+                info!(
+                    "reused connection to {} failed: {}, retry with a new connection",
+                    host, e
+                );
+            }
+            Err(UpstreamError::NoResponse(e)) | Err(UpstreamError::Failed(e)) => {
+                error!("{}", e);
+                http::bad_gateway(stream).await;
+                return false;
+            }
+        }
+    };
+    // HEAD 请求的响应没有实体内容
+    if req.method == Method::HEAD {
+        res.body = http::Body::Empty;
+    }
+    if res.code == 101 && !upgrade {
+        error!("unexpected 101 response from {}", host);
+        http::bad_gateway(stream).await;
+        return false;
+    }
+    // 需要在修改头部之前判断服务端是否允许保持连接，101 之后的连接不再是 HTTP
+    let reusable = res.code != 101 && res.keep_alive() && res.body != http::Body::Close;
+
+    // 需要检查实体内容时先读取开头的一部分，超过限制的部分不检查
+    let mut inspected = None;
+    if response::needs_body(rules, &res) {
+        let mut decoder = http::BodyDecoder::new(res.body);
+        match decoder
+            .read_to_limit(&mut client, cfg.server.max_inspect_body_size)
+            .await
+        {
+            Ok(data) => inspected = Some((decoder, data)),
+            Err(e) => {
+                error!("read response body failed: {}", e);
+                return false;
+            }
+        }
+    }
+
+    // filter response，压缩的实体内容解码之后检查，无法解码时使用 policy.default
+    let limit = cfg.server.max_inspect_body_size;
+    let decoded = inspected
+        .as_ref()
+        .map(|(decoder, data)| response::decode_body(&res, data, decoder.is_finished(), limit));
+    let verdict = match &decoded {
+        Some(Err(e)) => {
+            info!(
+                "response body of {} can not be inspected: {}, use policy default",
+                req.path(),
+                e
+            );
+            response::uninspectable(rules)
+        }
+        Some(Ok(body)) => response::evaluate(rules, &res, Some(body)),
+        None => response::evaluate(rules, &res, None),
+    };
+    log_audit("response", &verdict, &req);
+    if verdict.status == FilterStatus::Reject {
+        let request_id = utils::request_id();
+        info!(
+            "[{}] reject Response, {}: {:?}",
+            request_id,
+            verdict.reason(),
+            res.string()
+        );
+        reject(stream, &verdict, &req, client_addr, &request_id).await;
+        return false;
+    }
+
+    if cfg.server.auth.enable {
+        info!("user `{}` visited  {}", username, req.path());
+    } else {
+        info!("visited {}", req.path());
+    }
+
+    // 服务端同意升级协议之后连接不再是 HTTP，转发 101 之后双向转发数据
+    if res.code == 101 {
+        res.headers.remove("Proxy-Connection");
+        res.headers.remove("Keep-Alive");
+        res.headers.insert("Connection", "upgrade");
+        if let Err(e) = stream.write_all(&res.as_bytes()).await {
+            error!("wirte stream failed: {}", e);
+            return false;
+        };
+        // 服务端缓冲中已经读取的数据同样会被转发
+        let (mut server_reader, mut server_writer) = io::split(client);
+        let transferred = tunnel::relay(
+            reader,
+            stream,
+            &mut server_reader,
+            &mut server_writer,
+            tunnel_idle_timeout(cfg),
+        )
+        .await;
+        if let Some(e) = transferred.error {
+            error!("upgraded connection to {} failed: {}", host, e);
+        }
+        info!(
+            "upgraded connection to {} closed, sent {} bytes, received {} bytes",
+            host, transferred.sent, transferred.received
+        );
+        return false;
+    }
+
+    // 实体内容已经全部读取时使用 Content-Length 重新发送
+    if let Some((decoder, data)) = &inspected {
+        if decoder.is_finished() && res.body != http::Body::Empty {
+            res.headers.remove("Transfer-Encoding");
+            res.headers
+                .insert("Content-Length", &data.len().to_string());
+            res.body = http::Body::Length(data.len() as u64);
+        }
+    }
+
+    // 响应以连接关闭作为结束时，客户端连接也无法继续使用
+    let keep_alive = keep_alive && res.body != http::Body::Close;
+    res.headers.remove("Proxy-Connection");
+    res.headers.remove("Keep-Alive");
+    res.headers.insert(
+        "Connection",
+        if keep_alive { "keep-alive" } else { "close" },
+    );
+
+    if let Err(e) = stream.write_all(&res.as_bytes()).await {
+        error!("wirte stream failed: {}", e);
+        return false;
+    };
+
+    // 头部已经发送，实体内容边读边写，已经解码的 chunked 实体内容重新编码
+    let result = match inspected.as_mut() {
+        Some((decoder, data)) => {
+            let chunked = res.body == http::Body::Chunked;
+            decoder.forward(data, &mut client, stream, chunked).await
+        }
+        None => http::transfer_body(res.body, &mut client, stream).await,
+    };
+    if let Err(e) = result {
+        error!("transfer response body failed: {}", e);
+        return false;
+    }
+
+    // 请求和响应都已经完整转发，并且没有多余的数据时，放回连接池供后续的请求使用
+    if reusable && client.buffer().is_empty() {
+        state.upstream.put(&host, client.into_inner());
+    }
+
+    if let Err(e) = stream.flush().await {
+        error!("flush stream failed: {}", e);
+        return false;
+    };
+
+    keep_alive
+}
+
+// 隧道没有数据传输时关闭的时间，0 表示不限制
+fn tunnel_idle_timeout(cfg: &Config) -> Option<Duration> {
+    match cfg.server.tunnel_idle_timeout {
+        0 => None,
+        t => Some(Duration::from_secs(t)),
+    }
+}
+
+// 与目的服务器交换数据失败的原因
+enum UpstreamError {
+    // 没有收到任何响应数据，可以使用新的连接重新发送
+    NoResponse(String),
+    Failed(String),
+}
+
+// 将请求发送到目的服务器，实体内容流式转发，返回读取到的最终响应的头部
+//
+// 101 之外的 1xx 响应不是最终的响应，转发给客户端之后继续读取下一个响应
+async fn exchange(
+    mut server: TcpStream,
+    req: &mut http::Request,
+    request_body: Option<&mut (http::BodyDecoder, Vec<u8>)>,
+    reader: &mut BufReader<OwnedReadHalf>,
+    stream: &mut OwnedWriteHalf,
+) -> Result<(BufReader<TcpStream>, http::Response), UpstreamError> {
+    if let Err(e) = server.write_all(&req.as_bytes()).await {
+        return Err(UpstreamError::NoResponse(format!(
+            "send http request failed: {}",
+            e
+        )));
+    }
+    let result = match request_body {
+        Some((decoder, data)) => {
+            let chunked = req.body == http::Body::Chunked;
+            decoder.forward(data, reader, &mut server, chunked).await
+        }
+        None => http::transfer_body(req.body, reader, &mut server).await,
+    };
+    if let Err(e) = result {
+        return Err(UpstreamError::NoResponse(format!(
+            "send http request body failed: {}",
+            e
+        )));
+    }
+    if let Err(e) = server.flush().await {
+        return Err(UpstreamError::NoResponse(format!(
+            "flush data failed: {}",
+            e
+        )));
+    }
+
+    let mut server = BufReader::new(server);
+    match server.fill_buf().await {
+        Ok([]) => {
+            return Err(UpstreamError::NoResponse(
+                "connection closed by server".to_string(),
+            ))
+        }
+        Ok(_) => {}
+        Err(e) => {
+            return Err(UpstreamError::NoResponse(format!(
+                "read response failed: {}",
+                e
+            )))
+        }
+    }
+    loop {
+        // 解析收到的 HTTP 响应行和头部
+        let mut res = match http::parse_response(&mut server).await {
+            Ok(res) => res,
+            Err(e) => {
+                return Err(UpstreamError::Failed(format!(
+                    "parse response failed: {}",
+                    e
+                )))
+            }
+        };
+        if !(100..200).contains(&res.code) || res.code == 101 {
+            return Ok((server, res));
+        }
+        // HTTP/1.0 的客户端不支持 1xx 响应
+        if req.version == http::HttpVersion::Http1 {
+            continue;
+        }
+        if let Err(e) = stream.write_all(&res.as_bytes()).await {
+            return Err(UpstreamError::Failed(format!("write stream failed: {}", e)));
+        }
+    }
+}
+
+// 鉴权没有通过的原因
+enum AuthError {
+    // 没有携带鉴权信息
+    Missing,
+    // digest 的 nonce 过期，客户端使用新的 nonce 重试，不计入失败次数
+    Stale,
+    // 客户端地址或者用户名被锁定，需要等待的时间
+    Locked(Duration),
+    // 鉴权失败，计入失败次数，包含无法确定时为 None 的用户名以及失败的原因
+    Failed(Option<String>, String),
+}
+
+// 验证 Proxy-Authorization，返回用户名以及使用 JWT 鉴权时 token 对应的用户
+async fn authenticate(
+    state: &State,
+    cfg: &Config,
+    rules: &CompiledRuleSet,
+    req: &http::Request,
+) -> Result<(String, Option<Identity>), AuthError> {
+    let failed = |user: Option<&str>, reason: String| {
+        Err(AuthError::Failed(user.map(|u| u.to_string()), reason))
+    };
+    let (scheme, credentials) = match req.headers.get("Proxy-Authorization") {
+        Some(header) => match auth::parse_authorization(header) {
+            Some(parsed) => parsed,
+            None => return failed(None, "malformed Proxy-Authorization".to_string()),
+        },
+        None => return Err(AuthError::Missing),
+    };
+    // 用户名被锁定时不验证密码
+    let check_locked = |user: &str| match state.failures.locked(None, Some(user)) {
+        Some(wait) => Err(AuthError::Locked(wait)),
+        None => Ok(()),
+    };
+
+    // 配置了 jwt 时与 scheme 同时生效
+    if scheme.eq_ignore_ascii_case("Bearer") && cfg.server.auth.jwt.is_some() {
+        return match rules.verify_token(credentials) {
+            Ok(user) => Ok((user.name.clone(), Some(user))),
+            Err(e) => failed(None, e),
+        };
+    }
+    match cfg.server.auth.scheme {
+        Scheme::Basic if scheme.eq_ignore_ascii_case("Basic") => {
+            let (username, password) = match utils::decode(&credentials.to_string()) {
+                Ok(res) => res,
+                Err(e) => return failed(None, e.to_string()),
+            };
+            check_locked(&username)?;
+            // 验证用户名和密码
+            if !rules.authenticate(&username, &password).await {
+                return failed(Some(&username), "invalid username or password".to_string());
+            }
+            Ok((username, None))
+        }
+        Scheme::Digest if scheme.eq_ignore_ascii_case("Digest") => {
+            let credentials = match digest::Credentials::parse(credentials) {
+                Some(credentials) => credentials,
+                None => return failed(None, "malformed digest credentials".to_string()),
+            };
+            check_locked(&credentials.username)?;
+            let method = req.method.to_string();
+            match digest::authenticate(
+                &state.nonces,
+                &cfg.server.auth,
+                rules.users(),
+                &credentials,
+                &method,
+                &req.path,
+            ) {
+                Ok(()) => Ok((credentials.username, None)),
+                Err(DigestError::Stale) => Err(AuthError::Stale),
+                Err(e) => failed(Some(&credentials.username), format!("digest {:?}", e)),
+            }
+        }
+        _ => failed(None, format!("unsupported scheme `{}`", scheme)),
+    }
+}
+
+// 记录 audit 模式的规则生效时的结果
+fn log_audit(kind: &str, verdict: &Verdict, req: &http::Request) {
+    if let Some((rule, status)) = &verdict.audit {
+        let would = match status {
+            FilterStatus::Reject => "rejected",
+            FilterStatus::Forward => "forwarded",
+        };
+        info!(
+            "audit: {} of {} {} would be {} by rule `{}`",
+            kind,
+            req.method,
+            req.path(),
+            would,
+            rule
+        );
+    }
+}
+
+// 拒绝请求，返回规则配置的页面，没有配置时返回 403 Forbidden
+async fn reject(
+    stream: &mut OwnedWriteHalf,
+    verdict: &Verdict<'_>,
+    req: &http::Request,
+    client_addr: Option<IpAddr>,
+    request_id: &str,
+) {
+    let page = match verdict.block {
+        Some(page) => page,
+        None => {
+            http::forbidden(stream).await;
+            return;
+        }
+    };
+    let url = req.path();
+    let method = req.method.to_string();
+    let client = client_addr.map(|c| c.to_string()).unwrap_or_default();
+    let vars = [
+        ("rule", verdict.rule.unwrap_or("default")),
+        ("url", url.as_str()),
+        ("method", method.as_str()),
+        ("host", req.host().unwrap_or("")),
+        ("client", client.as_str()),
+        ("request_id", request_id),
+    ];
+    http::send_and_close(stream, &page.render(&vars)).await;
+}
+
+// 连接到目的服务器，依次尝试解析出来的每一个地址
+async fn connect(host: &str) -> Option<TcpStream> {
+    let socket_addrs = match tokio::net::lookup_host(host).await {
+        Ok(addrs) => addrs,
+        Err(_e) => {
+            error!("convert to socket addrs failed, host: {}", host);
+            return None;
+        }
+    };
+
+    // 遍历一遍找到一个连接成功的 TcpStream
+    for addr in socket_addrs {
+        match time::timeout(Duration::from_secs(4), TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => return Some(stream),
+            Ok(Err(err)) => {
+                info!("connect to socket failed: {}, try another", err);
+                continue;
+            }
+            Err(_) => {
+                info!("connect to {} timeout, try another", addr);
+                continue;
+            }
+        };
+    }
+    None
+}
+
+// 在随机端口上启动代理，返回监听的地址
+#[cfg(test)]
+async fn start_proxy(config: Config) -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state = Arc::new(State::new(Arc::new(config)).unwrap());
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handle_stream(stream, state.clone()));
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn connect_target_test() {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    let proxy = start_proxy(Config::default()).await;
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    // 没有监听的端口
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let closed_port = closed.local_addr().unwrap().port();
+    drop(closed);
+
+    // Host 头部与请求行不同时连接请求行中的地址，而不是 Host 头部中的地址
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    let req = format!(
+        "CONNECT 127.0.0.1:{} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\n\r\n",
+        closed_port, port
+    );
+    client.write_all(req.as_bytes()).await.unwrap();
+    let mut buf = Vec::new();
+    client.read_to_end(&mut buf).await.unwrap();
+    assert!(buf.is_empty());
+    assert!(time::timeout(Duration::from_millis(100), server.accept())
+        .await
+        .is_err());
+
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    let req = format!(
+        "CONNECT 127.0.0.1:{} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\n\r\n",
+        port, closed_port
+    );
+    client.write_all(req.as_bytes()).await.unwrap();
+    let mut buf = [0; 64];
+    let size = client.read(&mut buf).await.unwrap();
+    assert!(buf[..size].starts_with(b"HTTP/1.1 200"));
+    time::timeout(Duration::from_secs(1), server.accept())
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+// 读取请求或者响应的头部，直到空行
+#[cfg(test)]
+async fn read_head<R>(reader: &mut R) -> String
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    let mut head = String::new();
+    loop {
+        let size = reader.read_line(&mut head).await.unwrap();
+        if size == 0 || head.ends_with("\r\n\r\n") {
+            return head;
+        }
+    }
+}
+
+// 读取代理返回的数据，直到以 end 结尾
+#[cfg(test)]
+async fn read_response(stream: &mut TcpStream, end: &str) -> String {
+    use tokio::io::AsyncReadExt;
+
+    let mut buf = Vec::new();
+    let mut data = [0; 256];
+    while !buf.ends_with(end.as_bytes()) {
+        let size = stream.read(&mut data).await.unwrap();
+        assert!(size > 0, "{}", String::from_utf8_lossy(&buf));
+        buf.extend_from_slice(&data[..size]);
+    }
+    String::from_utf8(buf).unwrap()
+}
+
+#[tokio::test]
+async fn upstream_exchange_test() {
+    use tokio::net::TcpListener;
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    let upstream = tokio::spawn(async move {
+        // 先返回 100 Continue，最终的响应之后连接放回连接池
+        let (stream, _) = server.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        read_head(&mut stream).await;
+        stream
+            .write_all(
+                b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfirst",
+            )
+            .await
+            .unwrap();
+        // 复用这个连接的请求没有任何响应，代理使用新的连接重试
+        read_head(&mut stream).await;
+        drop(stream);
+        let (stream, _) = server.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        read_head(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nsecond")
+            .await
+            .unwrap();
+        stream
+    });
+
+    let proxy = start_proxy(Config::default()).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    let req = format!(
+        "GET http://127.0.0.1:{0}/ HTTP/1.1\r\nHost: 127.0.0.1:{0}\r\n\r\n",
+        port
+    );
+    client.write_all(req.as_bytes()).await.unwrap();
+    let res = read_response(&mut client, "first").await;
+    assert!(res.starts_with("HTTP/1.1 100 "));
+    assert!(res.contains("\r\n\r\nHTTP/1.1 200 "));
+    client.write_all(req.as_bytes()).await.unwrap();
+    let res = read_response(&mut client, "second").await;
+    assert!(res.starts_with("HTTP/1.1 200 "));
+    upstream.await.unwrap();
+}
+
+#[tokio::test]
+async fn upgrade_test() {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    let upstream = tokio::spawn(async move {
+        // 101 之后的数据与响应头部一起发送，代理需要转发已经读取到缓冲中的数据
+        let (stream, _) = server.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let head = read_head(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\nhello")
+            .await
+            .unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        stream.write_all(b"pong").await.unwrap();
+        head
+    });
+
+    let proxy = start_proxy(Config::default()).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    let req = format!(
+        "GET http://127.0.0.1:{0}/ HTTP/1.1\r\nHost: 127.0.0.1:{0}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n",
+        port
+    );
+    client.write_all(req.as_bytes()).await.unwrap();
+    let res = read_response(&mut client, "hello").await;
+    assert!(res.starts_with("HTTP/1.1 101 "));
+    assert!(res.contains("Connection: upgrade\r\n"));
+    assert!(res.contains("Upgrade: websocket\r\n"));
+    client.write_all(b"ping").await.unwrap();
+    let res = read_response(&mut client, "pong").await;
+    assert_eq!(res, "pong");
+
+    // 升级协议需要的头部原样发送给服务端
+    let head = upstream.await.unwrap();
+    assert!(head.contains("Connection: upgrade\r\n"), "{}", head);
+    assert!(head.contains("Upgrade: websocket\r\n"), "{}", head);
+}
+
+#[tokio::test]
+async fn strip_proxy_authorization_test() {
+    use tokio::net::TcpListener;
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    let upstream = tokio::spawn(async move {
+        let (stream, _) = server.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let head = read_head(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await
+            .unwrap();
+        head
+    });
+
+    let mut config = Config::default();
+    config.server.auth.enable = true;
+    config.server.auth.username = "rust".to_string();
+    config.server.auth.password = "proxy".to_string();
+    let proxy = start_proxy(config).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    let req = format!(
+        "GET http://127.0.0.1:{0}/ HTTP/1.1\r\nHost: 127.0.0.1:{0}\r\nProxy-Authorization: Basic cnVzdDpwcm94eQ==\r\n\r\n",
+        port
+    );
+    client.write_all(req.as_bytes()).await.unwrap();
+    let res = read_response(&mut client, "ok").await;
+    assert!(res.starts_with("HTTP/1.1 200 "));
+
+    let head = upstream.await.unwrap();
+    assert!(head.starts_with("GET "), "{}", head);
+    assert!(!head.to_ascii_lowercase().contains("proxy-authorization"));
+}
+
+#[tokio::test]
+async fn compressed_response_test() {
+    use crate::config::{Action, BodyPattern, Request, RequestDeny, Response, ResponseDeny};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use tokio::net::TcpListener;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(b"password: hunter2").unwrap();
+    let gzip = encoder.finish().unwrap();
+
+    // 每个连接返回一个响应
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let responses = [("gzip", gzip.clone()), ("br", b"password".to_vec())];
+        for (encoding, body) in responses.iter().cycle() {
+            let (stream, _) = server.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            read_head(&mut stream).await;
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Encoding: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                encoding,
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        }
+    });
+
+    let mut config = Config::default();
+    config.server.max_inspect_body_size = 1024;
+    config.deny.response.push(Response {
+        name: "password".to_string(),
+        rule: ResponseDeny {
+            body: Some(BodyPattern {
+                regex: vec![],
+                contains: vec!["password".to_string()],
+            }),
+            ..ResponseDeny::default()
+        },
+        block: None,
+        mode: None,
+    });
+    let get = |proxy| async move {
+        use tokio::io::AsyncReadExt;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let req = format!(
+            "GET http://127.0.0.1:{0}/ HTTP/1.1\r\nHost: 127.0.0.1:{0}\r\nConnection: close\r\n\r\n",
+            port
+        );
+        client.write_all(req.as_bytes()).await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf).into_owned()
+    };
+
+    // gzip 解码之后匹配，不支持的压缩方式使用 policy.default
+    let proxy = start_proxy(config).await;
+    assert!(get(proxy).await.starts_with("HTTP/1.1 403 "));
+    assert!(get(proxy).await.starts_with("HTTP/1.1 200 "));
+
+    // 只允许包含 password 的响应
+    let mut config = Config::default();
+    config.server.max_inspect_body_size = 1024;
+    config.policy.default = Action::Deny;
+    config.allow.request.push(Request {
+        name: "local".to_string(),
+        rule: RequestDeny {
+            hosts: vec!["127.0.0.1".to_string()],
+            ..RequestDeny::default()
+        },
+        block: None,
+        mode: None,
+    });
+    config.allow.response.push(Response {
+        name: "password".to_string(),
+        rule: ResponseDeny {
+            body: Some(BodyPattern {
+                regex: vec![],
+                contains: vec!["password".to_string()],
+            }),
+            ..ResponseDeny::default()
+        },
+        block: None,
+        mode: None,
+    });
+    let proxy = start_proxy(config).await;
+    assert!(get(proxy).await.starts_with("HTTP/1.1 200 "));
+    assert!(get(proxy).await.starts_with("HTTP/1.1 403 "));
+}
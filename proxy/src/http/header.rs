@@ -0,0 +1,116 @@
+//! header.rs HTTP 头部集合
+
+/// HTTP 头部
+///
+/// 按照插入顺序保存所有的头部，同一个名字可以出现多次（比如 `Set-Cookie`），
+/// 查找的时候不区分名字的大小写，输出的时候保留原来的大小写
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap {
+            entries: Vec::new(),
+        }
+    }
+
+    /// 追加一个头部，已经存在的同名头部会被保留
+    pub fn append(&mut self, key: &str, value: &str) {
+        self.entries.push((key.to_string(), value.to_string()));
+    }
+
+    /// 设置头部的值
+    ///
+    /// 如果已经存在同名的头部，则替换第一个的值并删除其余的，
+    /// 否则追加到最后
+    pub fn insert(&mut self, key: &str, value: &str) {
+        match self
+            .entries
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(key))
+        {
+            Some(index) => {
+                self.entries[index].1 = value.to_string();
+                let rest = self.entries.split_off(index + 1);
+                self.entries.extend(
+                    rest.into_iter()
+                        .filter(|(k, _)| !k.eq_ignore_ascii_case(key)),
+                );
+            }
+            None => self.append(key, value),
+        }
+    }
+
+    /// 获取第一个同名头部的值
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// 按照顺序获取所有同名头部的值
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// 删除所有同名的头部，返回是否存在
+    pub fn remove(&mut self, key: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        len != self.entries.len()
+    }
+
+    /// 按照插入顺序遍历所有的头部
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[test]
+fn header_map_test() {
+    let mut headers = HeaderMap::new();
+    headers.append("Set-Cookie", "a=1");
+    headers.append("Content-Type", "text/html");
+    headers.append("set-cookie", "b=2");
+
+    // 不区分大小写
+    assert_eq!(headers.get("content-type"), Some("text/html"));
+    assert_eq!(headers.get("SET-COOKIE"), Some("a=1"));
+    assert_eq!(
+        headers.get_all("Set-Cookie").collect::<Vec<_>>(),
+        vec!["a=1", "b=2"]
+    );
+
+    // 保留插入顺序
+    let keys: Vec<_> = headers.iter().map(|(k, _)| k).collect();
+    assert_eq!(keys, vec!["Set-Cookie", "Content-Type", "set-cookie"]);
+
+    headers.insert("SET-COOKIE", "c=3");
+    assert_eq!(headers.len(), 2);
+    assert_eq!(
+        headers.iter().collect::<Vec<_>>(),
+        vec![("Set-Cookie", "c=3"), ("Content-Type", "text/html")]
+    );
+
+    assert!(headers.remove("content-type"));
+    assert!(!headers.contains_key("Content-Type"));
+    assert!(!headers.remove("Content-Type"));
+}
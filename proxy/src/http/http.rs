@@ -1,9 +1,14 @@
 //! http.rs 负责http协议的解析
 
+use super::header::HeaderMap;
 use log::error;
-use std::collections::HashMap;
 use std::fmt::{Display, Formatter, Result as FmtResult};
-use std::io::{BufRead, BufReader, Read};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
 
 use std::str;
 
@@ -213,8 +218,10 @@ enum CharType {
 //               +-----------+
 fn transform(current: State, input: CharType) -> State {
     match current {
+        // 没有任何头部，例如 100 Continue
         State::Init => match input {
-            CharType::Return | CharType::NewLine => State::Invalid,
+            CharType::Return => State::Return2,
+            CharType::NewLine => State::End,
             _ => State::More,
         },
         State::More => match input {
@@ -268,6 +275,423 @@ fn split_key_value(line: Vec<u8>) -> Result<(String, String), &'static str> {
     Err("wrong format")
 }
 
+/// 实体内容的传输方式
+///
+/// 解析时只读取请求行（响应行）和头部，实体内容根据传输方式
+/// 从对端流式地转发出去，不会整体缓存在内存中
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Body {
+    Empty,       // 没有实体内容
+    Length(u64), // 由 Content-Length 指定长度
+    Chunked,     // Transfer-Encoding: chunked
+    Close,       // 一直读取到连接关闭，只用于响应
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::Empty
+    }
+}
+
+// 头部最大的长度，防止恶意的请求占用过多内存
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+// chunk-size 行以及 trailer 中每一行的最大长度
+const MAX_LINE_SIZE: u64 = 8 * 1024;
+
+// 判断实体是否使用 chunked 传输编码，chunked 必须是最后一个编码
+//
+// 没有 Transfer-Encoding 头部时返回 None
+fn is_chunked(headers: &HeaderMap) -> Option<bool> {
+    // 多个 Transfer-Encoding 头部时以最后一个为准
+    let te = headers.get_all("Transfer-Encoding").last()?;
+    let coding = te.rsplit(',').next().unwrap_or("");
+    Some(coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// 读取一行数据，去掉末尾的 \r\n
+async fn read_line<R>(stream: &mut R) -> io::Result<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+{
+    let mut line = Vec::new();
+    let size = (&mut *stream)
+        .take(MAX_LINE_SIZE)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "unexpected end of chunked body",
+        ));
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(invalid_data("chunk line is too long"));
+    }
+    while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+// 解析 chunk-size [; chunk-ext]，忽略 chunk 扩展
+fn chunk_size(line: &[u8]) -> io::Result<u64> {
+    let line = match str::from_utf8(line) {
+        Ok(res) => res,
+        Err(_) => return Err(invalid_data("convert chunk size failed")),
+    };
+    let size = line.split(';').next().unwrap_or("").trim();
+    match u64::from_str_radix(size, 16) {
+        Ok(res) => Ok(res),
+        Err(_) => Err(invalid_data("parse chunk size failed")),
+    }
+}
+
+// 从 reader 中准确地拷贝 length 个字节到 writer 中
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, length: u64) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let size = tokio::io::copy(&mut (&mut *reader).take(length), writer).await?;
+    if size < length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "body is less than expected length",
+        ));
+    }
+    Ok(size)
+}
+
+/// 转发 chunked 编码的实体内容
+///
+/// 格式为若干个 `chunk-size [; chunk-ext] CRLF chunk-data CRLF`，
+/// 以大小为 0 的 chunk 结束，之后是可选的 trailer 头部以及一个空行。
+/// framing 为 true 时分块格式原样写入 writer，否则只写入解码后的数据，
+/// 返回实体数据（不包含分块格式）的长度
+async fn transfer_chunked<R, W>(reader: &mut R, writer: &mut W, framing: bool) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut total = 0;
+
+    loop {
+        let line = read_line(reader).await?;
+        let size = chunk_size(&line)?;
+        if framing {
+            writer.write_all(&line).await?;
+            writer.write_all(b"\r\n").await?;
+        }
+        if size == 0 {
+            break;
+        }
+
+        total += copy_exact(reader, writer, size).await?;
+        // chunk-data 之后紧跟着 CRLF
+        if !read_line(reader).await?.is_empty() {
+            return Err(invalid_data("chunk data is not followed by CRLF"));
+        }
+        if framing {
+            writer.write_all(b"\r\n").await?;
+        }
+    }
+
+    // trailer 头部，以空行结束
+    loop {
+        let line = read_line(reader).await?;
+        if framing {
+            writer.write_all(&line).await?;
+            writer.write_all(b"\r\n").await?;
+        }
+        if line.is_empty() {
+            break;
+        }
+    }
+
+    Ok(total)
+}
+
+/// 将实体内容从 reader 流式地转发到 writer
+///
+/// 每次只使用固定大小的缓冲区，返回转发的实体数据长度
+pub async fn transfer_body<R, W>(body: Body, reader: &mut R, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    match body {
+        Body::Empty => Ok(0),
+        Body::Length(length) => copy_exact(reader, writer, length).await,
+        Body::Chunked => transfer_chunked(reader, writer, true).await,
+        Body::Close => tokio::io::copy(reader, writer).await,
+    }
+}
+
+/// 读取完整的实体内容，chunked 编码会被解码
+///
+/// 超过 limit 个字节时返回错误，仅用于确实需要整个实体内容的场景
+pub async fn read_body<R>(body: Body, reader: &mut R, limit: u64) -> Result<Vec<u8>, &'static str>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+{
+    if let Body::Length(length) = body {
+        if length > limit {
+            return Err("body is larger than limit");
+        }
+    }
+
+    let mut writer = LimitWriter {
+        buf: Vec::new(),
+        limit,
+    };
+    let result = match body {
+        Body::Empty => Ok(0),
+        Body::Length(length) => copy_exact(reader, &mut writer, length).await,
+        Body::Chunked => transfer_chunked(reader, &mut writer, false).await,
+        Body::Close => tokio::io::copy(reader, &mut writer).await,
+    };
+    match result {
+        Ok(_) => Ok(writer.buf),
+        Err(e) if e.kind() == io::ErrorKind::Other => Err("body is larger than limit"),
+        Err(_) => Err("read body failed"),
+    }
+}
+
+// 限制写入数据大小的 Write
+struct LimitWriter {
+    buf: Vec<u8>,
+    limit: u64,
+}
+
+impl AsyncWrite for LimitWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if (self.buf.len() + data.len()) as u64 > self.limit {
+            return Poll::Ready(Err(io::Error::other("exceed limit")));
+        }
+        self.buf.extend_from_slice(data);
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// 实体内容的解码器，读取到的数据不包含 chunked 的分块格式
+///
+/// 可以先读取一部分内容进行检查，之后再继续读取剩下的部分
+#[derive(Debug)]
+pub struct BodyDecoder {
+    body: Body,
+    // Length 时为剩余的字节数，Chunked 时为当前 chunk 剩余的字节数
+    remaining: u64,
+    // Chunked 时是否已经读取过 chunk，之后的 chunk 之前需要先读取上一个 chunk 末尾的 CRLF
+    started: bool,
+    finished: bool,
+}
+
+impl BodyDecoder {
+    pub fn new(body: Body) -> BodyDecoder {
+        let (remaining, finished) = match body {
+            Body::Empty => (0, true),
+            Body::Length(length) => (length, length == 0),
+            Body::Chunked | Body::Close => (0, false),
+        };
+        BodyDecoder {
+            body,
+            remaining,
+            started: false,
+            finished,
+        }
+    }
+
+    /// 实体内容是否已经全部读取
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// 读取解码之后的数据，返回 0 表示实体内容已经结束
+    pub async fn read<R>(&mut self, reader: &mut R, buf: &mut [u8]) -> io::Result<usize>
+    where
+        R: AsyncBufRead + Unpin + ?Sized,
+    {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+        match self.body {
+            Body::Empty => Ok(0),
+            Body::Close => {
+                let size = reader.read(buf).await?;
+                self.finished = size == 0;
+                Ok(size)
+            }
+            Body::Length(_) => self.read_remaining(reader, buf).await,
+            Body::Chunked => {
+                if self.remaining == 0 {
+                    self.next_chunk(reader).await?;
+                    if self.finished {
+                        return Ok(0);
+                    }
+                }
+                self.read_remaining(reader, buf).await
+            }
+        }
+    }
+
+    /// 读取最多 limit 个字节的数据，是否读取完整通过 is_finished 判断
+    pub async fn read_to_limit<R>(&mut self, reader: &mut R, limit: usize) -> io::Result<Vec<u8>>
+    where
+        R: AsyncBufRead + Unpin + ?Sized,
+    {
+        let mut data = Vec::new();
+        let mut buf = vec![0; 8 * 1024];
+        while data.len() < limit {
+            let max = std::cmp::min(buf.len(), limit - data.len());
+            let size = self.read(reader, &mut buf[..max]).await?;
+            if size == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..size]);
+        }
+        // 刚好读取到 limit 时，chunked 编码的结束标记可能还没有读取
+        if self.body == Body::Chunked && self.remaining == 0 && !self.finished {
+            self.next_chunk(reader).await?;
+        }
+        Ok(data)
+    }
+
+    /// 将已经读取的 prefix 写入 writer，剩下的部分从 reader 中边读边写
+    ///
+    /// chunked 为 true 时按照 chunked 编码写入（原有的 trailer 会被丢弃），
+    /// 否则写入解码之后的数据，返回写入的实体数据长度
+    pub async fn forward<R, W>(
+        &mut self,
+        prefix: &[u8],
+        reader: &mut R,
+        writer: &mut W,
+        chunked: bool,
+    ) -> io::Result<u64>
+    where
+        R: AsyncBufRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let mut buf = vec![0; 16 * 1024];
+        let mut data = prefix;
+        let mut total = 0;
+        loop {
+            if chunked {
+                write_chunk(writer, data).await?;
+            } else {
+                writer.write_all(data).await?;
+            }
+            total += data.len() as u64;
+
+            let size = self.read(reader, &mut buf).await?;
+            if size == 0 {
+                break;
+            }
+            data = &buf[..size];
+        }
+        if chunked {
+            write_last_chunk(writer).await?;
+        }
+        Ok(total)
+    }
+
+    // 从当前的 chunk 或者 Content-Length 剩余的部分中读取数据
+    async fn read_remaining<R>(&mut self, reader: &mut R, buf: &mut [u8]) -> io::Result<usize>
+    where
+        R: AsyncBufRead + Unpin + ?Sized,
+    {
+        let max = std::cmp::min(buf.len() as u64, self.remaining) as usize;
+        let size = reader.read(&mut buf[..max]).await?;
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "body is less than expected length",
+            ));
+        }
+        self.remaining -= size as u64;
+        if self.remaining == 0 && self.body != Body::Chunked {
+            self.finished = true;
+        }
+        Ok(size)
+    }
+
+    // 读取下一个 chunk 的大小，大小为 0 时读取 trailer 并结束
+    async fn next_chunk<R>(&mut self, reader: &mut R) -> io::Result<()>
+    where
+        R: AsyncBufRead + Unpin + ?Sized,
+    {
+        // chunk-data 之后紧跟着 CRLF
+        if self.started && !read_line(reader).await?.is_empty() {
+            return Err(invalid_data("chunk data is not followed by CRLF"));
+        }
+        self.started = true;
+
+        let size = chunk_size(&read_line(reader).await?)?;
+        if size == 0 {
+            // 丢弃 trailer 头部，以空行结束
+            while !read_line(reader).await?.is_empty() {}
+            self.finished = true;
+        }
+        self.remaining = size;
+        Ok(())
+    }
+}
+
+/// 将 data 作为一个 chunk 写入 writer，data 为空时不写入任何内容
+pub async fn write_chunk<W>(writer: &mut W, data: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    if data.is_empty() {
+        return Ok(());
+    }
+    writer
+        .write_all(format!("{:x}\r\n", data.len()).as_bytes())
+        .await?;
+    writer.write_all(data).await?;
+    writer.write_all(b"\r\n").await
+}
+
+/// 写入 chunked 编码的结束标记
+pub async fn write_last_chunk<W>(writer: &mut W) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    writer.write_all(b"0\r\n\r\n").await
+}
+
+// Connection 头部中是否包含 token，不区分大小写
+fn has_token(connection: Option<&str>, token: &str) -> bool {
+    match connection {
+        Some(c) => c.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)),
+        None => false,
+    }
+}
+
+// 根据 HTTP 版本号和 Connection 头部判断是否保持连接
+fn keep_alive(version: &HttpVersion, connection: Option<&str>) -> bool {
+    match version {
+        HttpVersion::Http1 => has_token(connection, "keep-alive"),
+        _ => !has_token(connection, "close"),
+    }
+}
+
 /// HTTP 请求
 ///
 /// 代表一次 HTTP 请求的所有数据，包括请求行，请求头部，请求实体内容
@@ -276,13 +700,15 @@ pub struct Request {
     pub method: Method,
     pub path: String,
     pub version: HttpVersion,
-    pub headers: HashMap<String, String>,
-    pub body: Vec<u8>,
+    pub headers: HeaderMap,
+    pub body: Body,
     cache: Vec<u8>,
 }
 
 impl Request {
-    /// 将请求转换成符合协议规范的字节输出
+    /// 将请求行和头部转换成符合协议规范的字节输出
+    ///
+    /// 实体内容不包含在内，需要通过 `transfer_body` 进行转发
     pub fn as_bytes(&mut self) -> Vec<u8> {
         if self.cache.len() != 0 {
             return self.cache.clone();
@@ -306,7 +732,7 @@ impl Request {
         buf.push(newline);
 
         // headers
-        for (key, value) in &self.headers {
+        for (key, value) in self.headers.iter() {
             buf.append(&mut key.as_bytes().to_vec());
             buf.push(colon);
             buf.push(space);
@@ -317,14 +743,34 @@ impl Request {
         buf.push(ret);
         buf.push(newline);
 
-        // 实体内容
-        buf.append(&mut self.body);
         // 写入缓冲中，下一次调用直接返回
         self.cache = buf.clone();
 
         buf
     }
 
+    /// 客户端是否希望保持连接
+    ///
+    /// HTTP/1.1 默认保持连接，除非声明了 `Connection: close`，
+    /// HTTP/1.0 只有声明了 `Connection: keep-alive` 才会保持连接，
+    /// 部分客户端使用代理时发送的是 `Proxy-Connection`
+    pub fn keep_alive(&self) -> bool {
+        let connection = match self.headers.get("Connection") {
+            Some(c) => Some(c),
+            None => self.headers.get("Proxy-Connection"),
+        };
+        keep_alive(&self.version, connection)
+    }
+
+    /// 客户端是否请求升级协议，例如 WebSocket
+    ///
+    /// 需要同时有 `Upgrade` 头部以及 `Connection: upgrade`，HTTP/1.0 不支持升级协议
+    pub fn upgrade(&self) -> bool {
+        self.version != HttpVersion::Http1
+            && self.headers.get("Upgrade").is_some()
+            && has_token(self.headers.get("Connection"), "upgrade")
+    }
+
     pub fn path(&self) -> String {
         let p = &self.path;
         if self.method == Method::CONNECT {
@@ -335,7 +781,7 @@ impl Request {
         }
         match self.headers.get("Host") {
             Some(h) => {
-                let mut res = h.clone();
+                let mut res = h.to_string();
                 res.push_str(p);
                 return res;
             }
@@ -345,6 +791,18 @@ impl Request {
         }
     }
 
+    /// 请求的目的主机名，不包含端口
+    ///
+    /// CONNECT 请求使用请求行中的地址，其他请求使用 Host 头部
+    pub fn host(&self) -> Option<&str> {
+        let authority = if self.method == Method::CONNECT {
+            self.path.as_str()
+        } else {
+            self.headers.get("Host")?
+        };
+        Some(strip_port(authority))
+    }
+
     pub fn string(self) -> String {
         let mut res = String::new();
         res += &format!(
@@ -355,6 +813,36 @@ impl Request {
     }
 }
 
+/// host:port 没有端口时加上默认的端口
+///
+/// IPv6 地址只有 `]` 之后的 `:` 表示端口，没有方括号的 IPv6 地址加上方括号
+pub fn with_port(authority: &str, port: u16) -> String {
+    if let Some(rest) = authority.strip_prefix('[') {
+        return match rest.split_once(']') {
+            Some((_, p)) if p.starts_with(':') => authority.to_string(),
+            _ => format!("{}:{}", authority, port),
+        };
+    }
+    match authority.matches(':').count() {
+        0 => format!("{}:{}", authority, port),
+        1 => authority.to_string(),
+        _ => format!("[{}]:{}", authority, port),
+    }
+}
+
+// 去掉 host:port 中的端口，IPv6 地址去掉外面的方括号
+fn strip_port(authority: &str) -> &str {
+    if let Some(rest) = authority.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') && port.bytes().all(|b| b.is_ascii_digit()) => {
+            host
+        }
+        _ => authority,
+    }
+}
+
 /// HTTP 响应
 ///
 /// 代表HTTP 响应内容，包括响应行，响应头部，响应体
@@ -363,13 +851,15 @@ pub struct Response {
     pub version: HttpVersion,
     pub code: u16,
     pub text: String,
-    pub headers: HashMap<String, String>,
-    pub body: Vec<u8>,
+    pub headers: HeaderMap,
+    pub body: Body,
     cache: Vec<u8>,
 }
 
 impl Response {
-    /// 将响应转换成符合协议规范的字节输出
+    /// 将响应行和头部转换成符合协议规范的字节输出
+    ///
+    /// 实体内容不包含在内，需要通过 `transfer_body` 进行转发
     pub fn as_bytes(&mut self) -> Vec<u8> {
         if self.cache.len() > 0 {
             return self.cache.clone();
@@ -394,7 +884,7 @@ impl Response {
         buf.push(newline);
 
         // headers
-        for (key, value) in &self.headers {
+        for (key, value) in self.headers.iter() {
             buf.append(&mut key.as_bytes().to_vec());
             buf.push(colon);
             buf.push(space);
@@ -405,13 +895,15 @@ impl Response {
         buf.push(ret);
         buf.push(newline);
 
-        // body
-        buf.append(&mut self.body);
-
         self.cache = buf.clone();
         buf
     }
 
+    /// 服务端是否允许保持连接，规则与请求相同
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(&self.version, self.headers.get("Connection"))
+    }
+
     pub fn string(self) -> String {
         let mut res = String::new();
         res += &format!(
@@ -422,8 +914,11 @@ impl Response {
     }
 }
 
-/// 解析 HTTP 协议内容
-fn parse(stream: &mut dyn BufRead) -> Result<(HashMap<String, String>, Vec<u8>), &'static str> {
+/// 解析 HTTP 头部，实体内容留在 stream 中
+async fn parse<R>(stream: &mut R) -> Result<HeaderMap, &'static str>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+{
     // 每次读取一个字节
     let mut buf = [0; 1];
     // 数据保存
@@ -431,16 +926,22 @@ fn parse(stream: &mut dyn BufRead) -> Result<(HashMap<String, String>, Vec<u8>),
     // 当前的状态
     let mut state = State::Init;
     // 头部数据
-    let mut header = HashMap::new();
+    let mut header = HeaderMap::new();
+    // 已经读取的头部长度
+    let mut total = 0;
 
     loop {
-        let size = match stream.read(&mut buf) {
+        let size = match stream.read(&mut buf).await {
             Ok(s) => s,
             Err(_) => return Err("read stream failed"),
         };
         if size == 0 {
             break;
         }
+        total += size;
+        if total > MAX_HEADER_SIZE {
+            return Err("http header is too large");
+        }
         state = transform(state, to_char_type(buf[0]));
         if state == State::End {
             break;
@@ -459,7 +960,7 @@ fn parse(stream: &mut dyn BufRead) -> Result<(HashMap<String, String>, Vec<u8>),
         if buf[0] == 10 {
             let res = split_key_value(writer.clone())?;
 
-            header.insert(res.0.clone(), res.1.clone());
+            header.append(&res.0, &res.1);
             writer.clear();
             continue;
         }
@@ -467,32 +968,60 @@ fn parse(stream: &mut dyn BufRead) -> Result<(HashMap<String, String>, Vec<u8>),
         writer.push(buf[0]);
     }
 
-    let length: usize = match header.get("Content-Length") {
-        Some(length) => match length.parse() {
-            Ok(res) => res,
-            Err(_) => {
-                return Err("parse Content-Length failed");
-            }
-        },
-        None => 0,
-    };
-    let mut body = vec![0; length];
+    Ok(header)
+}
 
-    if length != 0 {
-        match stream.read_exact(&mut body) {
-            Ok(_) => {}
-            Err(_) => {
-                return Err("body is less than Content-Length");
-            }
-        }
+// 解析 Content-Length
+fn content_length(headers: &HeaderMap) -> Result<Option<u64>, &'static str> {
+    match headers.get("Content-Length") {
+        Some(length) => match length.trim().parse() {
+            Ok(res) => Ok(Some(res)),
+            Err(_) => Err("parse Content-Length failed"),
+        },
+        None => Ok(None),
     }
+}
 
-    Ok((header, body))
+// 请求实体的传输方式，chunked 编码优先于 Content-Length
+//
+// 最后一个传输编码不是 chunked 时无法确定请求的长度，返回错误（RFC 9112 6.3）
+fn request_body(headers: &HeaderMap) -> Result<Body, &'static str> {
+    match is_chunked(headers) {
+        Some(true) => return Ok(Body::Chunked),
+        Some(false) => return Err("last transfer coding of request is not chunked"),
+        None => {}
+    }
+    match content_length(headers)? {
+        Some(0) | None => Ok(Body::Empty),
+        Some(length) => Ok(Body::Length(length)),
+    }
 }
 
-pub fn parse_request(stream: &mut dyn Read) -> Result<Request, &str> {
-    let mut stream = BufReader::new(stream);
+// 响应实体的传输方式，1xx、204、304 响应没有实体内容，
+// 最后一个传输编码不是 chunked 或者没有长度信息时读取到连接关闭为止
+fn response_body(code: u16, headers: &HeaderMap) -> Result<Body, &'static str> {
+    if (100..200).contains(&code) || code == 204 || code == 304 {
+        return Ok(Body::Empty);
+    }
+    match is_chunked(headers) {
+        Some(true) => return Ok(Body::Chunked),
+        Some(false) => return Ok(Body::Close),
+        None => {}
+    }
+    match content_length(headers)? {
+        Some(0) => Ok(Body::Empty),
+        Some(length) => Ok(Body::Length(length)),
+        None => Ok(Body::Close),
+    }
+}
 
+/// 解析 HTTP 请求的请求行和头部
+///
+/// 实体内容仍然保留在 stream 中，可以通过 `transfer_body` 转发
+pub async fn parse_request<R>(stream: &mut R) -> Result<Request, &'static str>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+{
     // 每次读取一个字节
     let mut buf = [0; 1];
     // 保存每一行的内容，会重复利用
@@ -501,7 +1030,7 @@ pub fn parse_request(stream: &mut dyn Read) -> Result<Request, &str> {
 
     // 首先读取一行数据，里面是请求行或者响应行
     loop {
-        let size = match stream.read(&mut buf) {
+        let size = match stream.read(&mut buf).await {
             Ok(s) => s,
             Err(_) => return Err("read stream failed"),
         };
@@ -512,6 +1041,9 @@ pub fn parse_request(stream: &mut dyn Read) -> Result<Request, &str> {
         if buf[0] == 10 {
             break;
         }
+        if writer.len() > MAX_HEADER_SIZE {
+            return Err("http header is too large");
+        }
 
         // 13 -> \r
         if buf[0] == 13 {
@@ -535,19 +1067,30 @@ pub fn parse_request(stream: &mut dyn Read) -> Result<Request, &str> {
         }
     };
 
-    let (header, body) = parse(&mut stream)?;
+    let mut header = parse(stream).await?;
+    let body = request_body(&header)?;
+    // 使用 Transfer-Encoding 时忽略 Content-Length，转发之前删除，避免服务端使用不同的长度
+    if body == Body::Chunked {
+        header.remove("Content-Length");
+    }
 
     Ok(Request {
         method: request_header.method,
         path: request_header.path,
         version: request_header.version,
         headers: header,
-        body: body,
+        body,
         cache: vec![],
     })
 }
 
-pub fn parse_response(stream: &mut dyn BufRead) -> Result<Response, &str> {
+/// 解析 HTTP 响应的响应行和头部
+///
+/// 实体内容仍然保留在 stream 中，可以通过 `transfer_body` 转发
+pub async fn parse_response<R>(stream: &mut R) -> Result<Response, &'static str>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+{
     // 每次读取一个字节
     let mut buf = [0; 1];
     // 保存每一行的内容，会重复利用
@@ -558,7 +1101,7 @@ pub fn parse_response(stream: &mut dyn BufRead) -> Result<Response, &str> {
     loop {
         // println!("{:?}", String::from_utf8(writer.clone()));
 
-        let size = match stream.read(&mut buf) {
+        let size = match stream.read(&mut buf).await {
             Ok(s) => s,
             Err(_err) => {
                 return Err("read stream failed");
@@ -571,6 +1114,9 @@ pub fn parse_response(stream: &mut dyn BufRead) -> Result<Response, &str> {
         if buf[0] == 10 {
             break;
         }
+        if writer.len() > MAX_HEADER_SIZE {
+            return Err("http header is too large");
+        }
 
         // 13 -> \r
         if buf[0] == 13 {
@@ -591,14 +1137,18 @@ pub fn parse_response(stream: &mut dyn BufRead) -> Result<Response, &str> {
         Err(_) => return Err("parse http response header failed"),
     };
 
-    let (header, body) = parse(stream)?;
+    let mut header = parse(stream).await?;
+    let body = response_body(response_header.code, &header)?;
+    if header.get("Transfer-Encoding").is_some() {
+        header.remove("Content-Length");
+    }
 
     Ok(Response {
         version: response_header.version,
         code: response_header.code,
         text: response_header.text,
         headers: header,
-        body: body,
+        body,
         cache: vec![],
     })
 }
@@ -615,33 +1165,355 @@ fn method_test() {
     assert_eq!(HttpVersion::parse("HTTP/3").unwrap(), HttpVersion::Http3);
 }
 
-#[test]
-fn parse_request_test() {
+#[tokio::test]
+async fn parse_request_test() {
     use std::io::Cursor;
     let request_str = "POST /login HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello".as_bytes();
     let mut request_ = Cursor::new(request_str);
-    let request = parse_request(&mut request_);
+    let request = parse_request(&mut request_).await;
     assert!(request.is_ok());
 
     let request = request.unwrap();
 
     assert_eq!(request.path, "/login");
-    assert_eq!(request.body, "hello".as_bytes());
+    assert_eq!(request.body, Body::Length(5));
     assert!(request.headers.get("Content-Length").is_some());
-    assert_eq!(request.headers.get("Content-Length").unwrap(), "5")
+    assert_eq!(request.headers.get("Content-Length").unwrap(), "5");
+    // 实体内容保留在 stream 中
+    assert_eq!(
+        read_body(request.body, &mut request_, 1024).await.unwrap(),
+        "hello".as_bytes()
+    );
 }
 
-#[test]
-fn parse_response_test() {
+#[tokio::test]
+async fn parse_transfer_encoding_test() {
+    use std::io::Cursor;
+
+    // 同时有 Transfer-Encoding 和 Content-Length 时使用 chunked，并且删除 Content-Length
+    let request_str =
+        "POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+    let mut request_ = Cursor::new(request_str.as_bytes());
+    let request = parse_request(&mut request_).await.unwrap();
+    assert_eq!(request.body, Body::Chunked);
+    assert_eq!(request.headers.get("Content-Length"), None);
+    assert_eq!(
+        read_body(request.body, &mut request_, 1024).await.unwrap(),
+        "hello".as_bytes()
+    );
+
+    // 最后一个编码不是 chunked 的请求无法确定长度
+    for te in ["chunked, gzip", "gzip", ""] {
+        let request_str = format!(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: {}\r\nContent-Length: 5\r\n\r\nhello",
+            te
+        );
+        assert!(parse_request(&mut Cursor::new(request_str.as_bytes()))
+            .await
+            .is_err());
+    }
+
+    // 响应读取到连接关闭为止
+    let response_str =
+        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked, gzip\r\nContent-Length: 5\r\n\r\nhello";
+    let response = parse_response(&mut Cursor::new(response_str.as_bytes()))
+        .await
+        .unwrap();
+    assert_eq!(response.body, Body::Close);
+    assert_eq!(response.headers.get("Content-Length"), None);
+}
+
+#[tokio::test]
+async fn parse_response_test() {
     use std::io::Cursor;
     let response_str = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello".as_bytes();
-    let mut response = Cursor::new(response_str);
-    let response = parse_response(&mut response);
+    let mut response_ = Cursor::new(response_str);
+    let response = parse_response(&mut response_).await;
     assert!(response.is_ok());
 
     let response = response.unwrap();
 
-    assert_eq!(response.body, "hello".as_bytes());
+    assert_eq!(response.body, Body::Length(5));
     assert!(response.headers.get("Content-Length").is_some());
-    assert_eq!(response.headers.get("Content-Length").unwrap(), "5")
+    assert_eq!(response.headers.get("Content-Length").unwrap(), "5");
+    assert_eq!(
+        read_body(response.body, &mut response_, 1024)
+            .await
+            .unwrap(),
+        "hello".as_bytes()
+    );
+
+    // 没有长度信息的响应读取到连接关闭为止
+    let response_str = "HTTP/1.0 200 OK\r\nServer: test\r\n\r\nhello world".as_bytes();
+    let mut response_ = Cursor::new(response_str);
+    let response = parse_response(&mut response_).await.unwrap();
+    assert_eq!(response.body, Body::Close);
+    assert!(read_body(response.body, &mut response_, 5).await.is_err());
+
+    // 204 响应没有实体内容
+    let response_str = "HTTP/1.1 204 No Content\r\nServer: test\r\n\r\n".as_bytes();
+    let response = parse_response(&mut Cursor::new(response_str))
+        .await
+        .unwrap();
+    assert_eq!(response.body, Body::Empty);
+}
+
+#[tokio::test]
+async fn parse_chunked_test() {
+    use std::io::Cursor;
+    let response_str = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n".as_bytes();
+    let mut response_ = Cursor::new(response_str);
+    let response = parse_response(&mut response_).await;
+    assert!(response.is_ok());
+
+    let response = response.unwrap();
+    assert_eq!(response.body, Body::Chunked);
+    assert_eq!(
+        read_body(response.body, &mut response_, 1024)
+            .await
+            .unwrap(),
+        "hello, world".as_bytes()
+    );
+
+    // 带有 trailer 的请求
+    let request_str = "POST /upload HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\na\r\n0123456789\r\n0\r\nExpires: never\r\nX-Checksum: 42\r\n\r\nGET".as_bytes();
+    let mut request_ = Cursor::new(request_str);
+    let request = parse_request(&mut request_).await.unwrap();
+    assert_eq!(request.body, Body::Chunked);
+    assert_eq!(
+        read_body(request.body, &mut request_, 10).await.unwrap(),
+        "0123456789".as_bytes()
+    );
+    // trailer 之后的内容不会被读取
+    assert_eq!(request_.position() as usize, request_str.len() - 3);
+
+    // 超过限制的长度
+    let mut request_ = Cursor::new(request_str);
+    let request = parse_request(&mut request_).await.unwrap();
+    assert!(read_body(request.body, &mut request_, 9).await.is_err());
+
+    // chunk 长度不符合
+    let request_str =
+        "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nz\r\nhello\r\n0\r\n\r\n".as_bytes();
+    let mut request_ = Cursor::new(request_str);
+    let request = parse_request(&mut request_).await.unwrap();
+    assert!(read_body(request.body, &mut request_, 1024).await.is_err());
+}
+
+#[tokio::test]
+async fn transfer_body_test() {
+    use std::io::Cursor;
+    let body = "5\r\nhello\r\n7;ext\r\n, world\r\n0\r\nExpires: never\r\n\r\n";
+    let response_str = format!(
+        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+        body
+    );
+    let mut response_ = Cursor::new(response_str.as_bytes());
+    let mut response = parse_response(&mut response_).await.unwrap();
+
+    let mut output = response.as_bytes();
+    assert!(String::from_utf8_lossy(&output).ends_with("chunked\r\n\r\n"));
+
+    // chunked 编码原样转发，包括扩展和 trailer
+    let size = transfer_body(response.body, &mut response_, &mut output)
+        .await
+        .unwrap();
+    assert_eq!(size, 12);
+    assert!(String::from_utf8_lossy(&output).ends_with(body));
+
+    // Content-Length 不足时返回错误
+    let request_str = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello".as_bytes();
+    let mut request_ = Cursor::new(request_str);
+    let request = parse_request(&mut request_).await.unwrap();
+    let mut output = Vec::new();
+    assert!(transfer_body(request.body, &mut request_, &mut output)
+        .await
+        .is_err());
+
+    // 头部过大
+    let request_str = format!(
+        "GET / HTTP/1.1\r\nX-Large: {}\r\n\r\n",
+        "a".repeat(MAX_HEADER_SIZE)
+    );
+    assert!(parse_request(&mut Cursor::new(request_str.as_bytes()))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn headers_order_test() {
+    use std::io::Cursor;
+    let response_str =
+        "HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\nContent-Length: 0\r\nset-cookie: b=2\r\n\r\n";
+    let mut response = parse_response(&mut Cursor::new(response_str.as_bytes()))
+        .await
+        .unwrap();
+
+    // 重复的头部不会丢失，并且保持原来的顺序
+    assert_eq!(
+        response.headers.get_all("Set-Cookie").collect::<Vec<_>>(),
+        vec!["a=1", "b=2"]
+    );
+    assert_eq!(
+        String::from_utf8(response.as_bytes()).unwrap(),
+        "HTTP/1.1 200 OK \r\nSet-Cookie: a=1\r\nContent-Length: 0\r\nset-cookie: b=2\r\n\r\n"
+    );
+}
+
+#[tokio::test]
+async fn lowercase_headers_test() {
+    use std::io::Cursor;
+    // 头部名字的大小写不影响实体内容的解析
+    let request_str = "POST /login HTTP/1.1\r\nhost: example.com\r\ncontent-length: 5\r\n\r\nhello";
+    let mut request_ = Cursor::new(request_str.as_bytes());
+    let request = parse_request(&mut request_).await.unwrap();
+    assert_eq!(request.body, Body::Length(5));
+    assert_eq!(request.path(), "example.com/login");
+
+    let response_str = "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n0\r\n\r\n";
+    let response = parse_response(&mut Cursor::new(response_str.as_bytes()))
+        .await
+        .unwrap();
+    assert_eq!(response.body, Body::Chunked);
+}
+
+#[test]
+fn keep_alive_test() {
+    let mut request = Request::default();
+    // HTTP/1.1 默认保持连接
+    assert!(request.keep_alive());
+
+    request.headers.insert("Connection", "Close");
+    assert!(!request.keep_alive());
+
+    request.headers.remove("Connection");
+    request.headers.insert("Proxy-Connection", "close");
+    assert!(!request.keep_alive());
+
+    // HTTP/1.0 默认关闭连接
+    let mut request = Request {
+        version: HttpVersion::Http1,
+        ..Default::default()
+    };
+    assert!(!request.keep_alive());
+
+    request.headers.insert("proxy-connection", "Keep-Alive");
+    assert!(request.keep_alive());
+
+    request.headers.insert("Connection", "Upgrade, keep-alive");
+    assert!(request.keep_alive());
+}
+
+#[test]
+fn request_upgrade_test() {
+    let mut request = Request::default();
+    request.headers.insert("Upgrade", "websocket");
+    assert!(!request.upgrade());
+    request.headers.insert("Connection", "keep-alive, Upgrade");
+    assert!(request.upgrade());
+    request.headers.remove("Upgrade");
+    assert!(!request.upgrade());
+
+    // HTTP/1.0 不支持升级协议
+    let mut request = Request {
+        version: HttpVersion::Http1,
+        ..Default::default()
+    };
+    request.headers.insert("Upgrade", "websocket");
+    request.headers.insert("Connection", "upgrade");
+    assert!(!request.upgrade());
+}
+
+#[test]
+fn with_port_test() {
+    assert_eq!(with_port("www.example.com", 80), "www.example.com:80");
+    assert_eq!(
+        with_port("www.example.com:8080", 80),
+        "www.example.com:8080"
+    );
+    assert_eq!(with_port("[::1]", 80), "[::1]:80");
+    assert_eq!(with_port("[::1]:8080", 80), "[::1]:8080");
+    assert_eq!(with_port("::1", 80), "[::1]:80");
+}
+
+#[test]
+fn response_keep_alive_test() {
+    let mut response = Response::default();
+    assert!(response.keep_alive());
+
+    response.headers.insert("connection", "close");
+    assert!(!response.keep_alive());
+
+    response.version = HttpVersion::Http1;
+    response.headers.insert("Connection", "keep-alive");
+    assert!(response.keep_alive());
+}
+
+#[test]
+fn request_host_test() {
+    let mut request = Request::default();
+    assert_eq!(request.host(), None);
+
+    request.headers.insert("Host", "www.example.com");
+    assert_eq!(request.host(), Some("www.example.com"));
+    request.headers.insert("Host", "www.example.com:8080");
+    assert_eq!(request.host(), Some("www.example.com"));
+    request.headers.insert("Host", "[::1]:8080");
+    assert_eq!(request.host(), Some("::1"));
+
+    request.method = Method::CONNECT;
+    request.path = "github.com:443".to_string();
+    assert_eq!(request.host(), Some("github.com"));
+}
+
+#[tokio::test]
+async fn body_decoder_test() {
+    use std::io::Cursor;
+
+    // 读取一部分之后继续转发剩下的部分，重新按照 chunked 编码
+    let body = "5\r\nhello\r\n7;ext\r\n, world\r\n0\r\nExpires: never\r\n\r\nnext";
+    let mut reader = Cursor::new(body.as_bytes());
+    let mut decoder = BodyDecoder::new(Body::Chunked);
+    let prefix = decoder.read_to_limit(&mut reader, 7).await.unwrap();
+    assert_eq!(prefix, b"hello, ");
+    assert!(!decoder.is_finished());
+    let mut output = Vec::new();
+    let size = decoder
+        .forward(&prefix, &mut reader, &mut output, true)
+        .await
+        .unwrap();
+    assert_eq!(size, 12);
+    assert_eq!(output, b"7\r\nhello, \r\n5\r\nworld\r\n0\r\n\r\n");
+    assert!(decoder.is_finished());
+    // 后续的数据不会被读取
+    assert_eq!(reader.position() as usize, body.len() - 4);
+
+    // 刚好读取到 limit 时同样读取结束标记
+    let mut reader = Cursor::new(body.as_bytes());
+    let mut decoder = BodyDecoder::new(Body::Chunked);
+    let data = decoder.read_to_limit(&mut reader, 12).await.unwrap();
+    assert_eq!(data, b"hello, world");
+    assert!(decoder.is_finished());
+
+    let mut reader = Cursor::new("hello world".as_bytes());
+    let mut decoder = BodyDecoder::new(Body::Length(5));
+    assert_eq!(
+        decoder.read_to_limit(&mut reader, 10).await.unwrap(),
+        b"hello"
+    );
+    assert!(decoder.is_finished());
+
+    let mut reader = Cursor::new("hello".as_bytes());
+    let mut decoder = BodyDecoder::new(Body::Length(10));
+    assert!(decoder.read_to_limit(&mut reader, 20).await.is_err());
+
+    let mut reader = Cursor::new("hello world".as_bytes());
+    let mut decoder = BodyDecoder::new(Body::Close);
+    let prefix = decoder.read_to_limit(&mut reader, 5).await.unwrap();
+    let mut output = Vec::new();
+    decoder
+        .forward(&prefix, &mut reader, &mut output, false)
+        .await
+        .unwrap();
+    assert_eq!(output, b"hello world");
 }
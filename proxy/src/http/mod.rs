@@ -1,6 +1,9 @@
 mod utils;
 pub use utils::*;
 
+mod header;
+pub use header::*;
+
 mod http;
 
 pub use http::*;
@@ -1,55 +1,149 @@
 use log::error;
-use std::io::Write;
-use std::net::{Shutdown, TcpStream};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 static HTTP_AUTH: &[u8] = "HTTP/1.1 401 Unauthorized\r\nConnection: close\r\n\r\n".as_bytes();
 static HTTP_FORBIDDEN: &[u8] = "HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n".as_bytes();
-static HTTP_PROXY_AUTH: &[u8] =
-    "HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic\r\n\r\n".as_bytes();
 static HTTP_NOT_SUPPORT: &[u8] = "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 31\r\n\r\nProxy do not support https Now".as_bytes();
 static HTTP_STATUS_OK: &[u8] = "HTTP/1.1 200 OK\r\nProxy-Connection: keep-alive\r\n\r\n".as_bytes();
+static HTTP_CONTINUE: &[u8] = "HTTP/1.1 100 Continue\r\n\r\n".as_bytes();
+static HTTP_BAD_REQUEST: &[u8] =
+    "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n".as_bytes();
+static HTTP_BAD_GATEWAY: &[u8] =
+    "HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\nContent-Length: 0\r\n\r\n".as_bytes();
 
-pub fn unauthorized(stream: &mut TcpStream) {
-    if let Err(err) = stream.write(HTTP_AUTH) {
+pub async fn unauthorized<W>(stream: &mut W)
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    if let Err(err) = stream.write_all(HTTP_AUTH).await {
         error!("write stream failed: {}", err);
     }
-    if let Err(err) = stream.shutdown(Shutdown::Both) {
+    if let Err(err) = stream.shutdown().await {
         error!("shutdown stream failed: {}", err);
     }
 }
 
-pub fn forbidden(stream: &mut TcpStream) {
-    if let Err(err) = stream.write(HTTP_FORBIDDEN) {
+pub async fn forbidden<W>(stream: &mut W)
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    if let Err(err) = stream.write_all(HTTP_FORBIDDEN).await {
         error!("write stream failed: {}", err);
     }
-    if let Err(err) = stream.shutdown(Shutdown::Both) {
+    if let Err(err) = stream.shutdown().await {
         error!("shutdown stream failed: {}", err);
     }
 }
 
-pub fn proxy_auth(stream: &mut TcpStream) {
-    if let Err(err) = stream.write(HTTP_PROXY_AUTH) {
+/// 无法解析的请求，发送之后关闭连接
+pub async fn bad_request<W>(stream: &mut W)
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    if let Err(err) = stream.write_all(HTTP_BAD_REQUEST).await {
         error!("write stream failed: {}", err);
     }
-    if let Err(err) = stream.shutdown(Shutdown::Both) {
+    if let Err(err) = stream.shutdown().await {
         error!("shutdown stream failed: {}", err);
     }
 }
 
-pub fn not_support_https(stream: &mut TcpStream) {
-    if let Err(err) = stream.write(HTTP_NOT_SUPPORT) {
+/// 没有从目的服务器收到响应
+pub async fn bad_gateway<W>(stream: &mut W)
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    if let Err(err) = stream.write_all(HTTP_BAD_GATEWAY).await {
         error!("write stream failed: {}", err);
     }
-    if let Err(err) = stream.shutdown(Shutdown::Both) {
+    if let Err(err) = stream.shutdown().await {
         error!("shutdown stream failed: {}", err);
     }
 }
 
-pub fn http_status_ok(stream: &mut TcpStream) {
-    if let Err(err) = stream.write(HTTP_STATUS_OK) {
+/// 发送完整的响应之后关闭写方向
+pub async fn send_and_close<W>(stream: &mut W, response: &[u8])
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    if let Err(err) = stream.write_all(response).await {
         error!("write stream failed: {}", err);
     }
-    if let Err(err) = stream.flush() {
+    if let Err(err) = stream.shutdown().await {
+        error!("shutdown stream failed: {}", err);
+    }
+}
+
+/// 407 响应，每个 challenge 对应一个 `Proxy-Authenticate` 头部
+pub async fn proxy_auth<W>(stream: &mut W, challenges: &[String])
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut response = String::from("HTTP/1.1 407 Proxy Authentication Required\r\n");
+    for challenge in challenges {
+        response.push_str("Proxy-Authenticate: ");
+        response.push_str(challenge);
+        response.push_str("\r\n");
+    }
+    response.push_str("Connection: close\r\nContent-Length: 0\r\n\r\n");
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        error!("write stream failed: {}", err);
+    }
+    if let Err(err) = stream.shutdown().await {
+        error!("shutdown stream failed: {}", err);
+    }
+}
+
+/// 鉴权失败的次数太多，客户端需要等待 retry_after 秒之后重试
+pub async fn too_many_requests<W>(stream: &mut W, retry_after: u64)
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let response = format!(
+        "HTTP/1.1 429 Too Many Requests\r\nRetry-After: {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+        retry_after
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        error!("write stream failed: {}", err);
+    }
+    if let Err(err) = stream.shutdown().await {
+        error!("shutdown stream failed: {}", err);
+    }
+}
+
+pub async fn not_support_https<W>(stream: &mut W)
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    if let Err(err) = stream.write_all(HTTP_NOT_SUPPORT).await {
+        error!("write stream failed: {}", err);
+    }
+    if let Err(err) = stream.shutdown().await {
+        error!("shutdown stream failed: {}", err);
+    }
+}
+
+pub async fn http_status_ok<W>(stream: &mut W)
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    if let Err(err) = stream.write_all(HTTP_STATUS_OK).await {
+        error!("write stream failed: {}", err);
+    }
+    if let Err(err) = stream.flush().await {
+        error!("flush stream failed: {}", err);
+    }
+}
+
+/// 告诉客户端继续发送实体内容，用于 `Expect: 100-continue`
+pub async fn http_continue<W>(stream: &mut W)
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    if let Err(err) = stream.write_all(HTTP_CONTINUE).await {
+        error!("write stream failed: {}", err);
+    }
+    if let Err(err) = stream.flush().await {
         error!("flush stream failed: {}", err);
     }
 }
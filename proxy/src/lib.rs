@@ -1,15 +1,17 @@
-#[macro_use]
-extern crate lazy_static;
-
 pub use config::Config;
 pub use server::Server;
 
+mod auth;
 mod banner;
 mod config;
+mod handler;
 mod iptables;
 mod log;
 mod pool;
+mod reload;
 mod server;
+mod stats;
+mod tunnel;
 mod utils;
 
 pub mod filter;
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use tokio::net::TcpStream;
+
+/// 到目的服务器的连接池
+///
+/// 按照 host:port 保存空闲的连接，服务端允许保持连接时，
+/// 响应结束之后连接会放回连接池中，供后续同一个目的地址的请求使用
+pub struct ConnectionPool {
+    idle: Mutex<HashMap<String, Vec<(TcpStream, Instant)>>>,
+    // 每个目的地址最多保存的空闲连接数量，0 表示不使用连接池
+    max_idle_per_host: usize,
+    // 空闲连接的过期时间
+    idle_timeout: Duration,
+}
+
+impl ConnectionPool {
+    pub fn new(max_idle_per_host: usize, idle_timeout: Duration) -> ConnectionPool {
+        ConnectionPool {
+            idle: Mutex::new(HashMap::new()),
+            max_idle_per_host,
+            idle_timeout,
+        }
+    }
+
+    /// 是否启用了连接池
+    pub fn enabled(&self) -> bool {
+        self.max_idle_per_host > 0
+    }
+
+    /// 取出一个到 host 的空闲连接
+    ///
+    /// 优先使用最近放回的连接，过期或者已经被服务端关闭的连接会被丢弃
+    pub fn get(&self, host: &str) -> Option<TcpStream> {
+        let mut idle = match self.idle.lock() {
+            Ok(idle) => idle,
+            Err(e) => {
+                error!("require connection pool lock failed: {}", e);
+                return None;
+            }
+        };
+        let conns = idle.get_mut(host)?;
+
+        let mut result = None;
+        while let Some((stream, since)) = conns.pop() {
+            if since.elapsed() >= self.idle_timeout {
+                continue;
+            }
+            if let Some(stream) = check_alive(stream) {
+                result = Some(stream);
+                break;
+            }
+        }
+        if conns.is_empty() {
+            idle.remove(host);
+        }
+        result
+    }
+
+    /// 将连接放回连接池
+    ///
+    /// 超过 max_idle_per_host 时丢弃最早放回的连接
+    pub fn put(&self, host: &str, stream: TcpStream) {
+        if !self.enabled() {
+            return;
+        }
+        let mut idle = match self.idle.lock() {
+            Ok(idle) => idle,
+            Err(e) => {
+                error!("require connection pool lock failed: {}", e);
+                return;
+            }
+        };
+
+        let timeout = self.idle_timeout;
+        let conns = idle.entry(host.to_string()).or_insert_with(Vec::new);
+        conns.retain(|(_, since)| since.elapsed() < timeout);
+        if conns.len() >= self.max_idle_per_host {
+            info!("too many idle connections to {}, drop the oldest", host);
+            conns.remove(0);
+        }
+        conns.push((stream, Instant::now()));
+    }
+}
+
+// 检查空闲连接是否仍然可用，不可用时返回 None
+//
+// 空闲连接上不应该有任何数据，读取到 EOF 说明服务端已经关闭了连接，
+// 转换成非阻塞的标准库连接进行检查，不需要等待
+fn check_alive(stream: TcpStream) -> Option<TcpStream> {
+    let stream = stream.into_std().ok()?;
+    let mut buf = [0; 1];
+    match stream.peek(&mut buf) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => TcpStream::from_std(stream).ok(),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn connection_pool_test() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    let pool = ConnectionPool::new(1, Duration::from_secs(60));
+    assert!(pool.get(&addr).is_none());
+
+    let first = TcpStream::connect(&addr).await.unwrap();
+    let (_server1, _) = listener.accept().await.unwrap();
+    let second = TcpStream::connect(&addr).await.unwrap();
+    let (server2, _) = listener.accept().await.unwrap();
+    let second_port = second.local_addr().unwrap().port();
+
+    // 超过数量限制时丢弃最早的连接
+    pool.put(&addr, first);
+    pool.put(&addr, second);
+    let conn = pool.get(&addr).unwrap();
+    assert_eq!(conn.local_addr().unwrap().port(), second_port);
+    assert!(pool.get(&addr).is_none());
+
+    // 服务端关闭的连接不会被取出
+    pool.put(&addr, conn);
+    drop(server2);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(pool.get(&addr).is_none());
+
+    // 过期的连接不会被取出
+    let pool = ConnectionPool::new(1, Duration::from_millis(0));
+    let conn = TcpStream::connect(&addr).await.unwrap();
+    let (_server3, _) = listener.accept().await.unwrap();
+    pool.put(&addr, conn);
+    assert!(pool.get(&addr).is_none());
+}
@@ -1,5 +1,3 @@
-pub use pool::ThreadPool;
+pub use connection::ConnectionPool;
 
-mod message;
-mod pool;
-mod worker;
+mod connection;
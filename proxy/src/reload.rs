@@ -0,0 +1,302 @@
+//! reload.rs 负责在运行时重新加载配置文件
+//!
+//! 收到 SIGHUP 信号或者配置文件被修改时重新读取配置，
+//! 检查通过之后替换当前的配置，已经建立的连接继续使用之前的配置
+
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::{error, info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
+
+use crate::config::Config;
+use crate::handler::State;
+
+/// 收到 SIGHUP 信号时重新加载配置
+pub async fn on_signal(state: Arc<State>, path: String) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("listen SIGHUP failed: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("received SIGHUP, reload {}", path);
+        reload(&state, &path);
+    }
+}
+
+/// 每隔 interval 检查一次配置文件的修改时间，修改之后重新加载配置
+pub async fn watch(state: Arc<State>, path: String, interval: Duration) {
+    let mut last = modified(&path);
+    let mut ticker = time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let current = modified(&path);
+        if current.is_none() || current == last {
+            continue;
+        }
+        last = current;
+        info!("{} modified, reload", path);
+        reload(&state, &path);
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// 重新加载配置，配置有误时继续使用之前的配置
+///
+/// 返回是否替换了配置
+pub fn reload(state: &State, path: &str) -> bool {
+    let (config, mut rules) = match Config::load_with_rules(path) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("reload config failed, keep the current one: {}", e);
+            return false;
+        }
+    };
+
+    let (old, old_rules) = state.snapshot();
+    let mut changes = changes(&old, &config);
+    // 配置没有变化时，模板文件的内容也可能发生了变化
+    let old_pages = old_rules.block_pages();
+    for (name, page) in rules.block_pages() {
+        if old_pages.iter().any(|(n, p)| *n == name && *p != page) {
+            changes.push(format!("block page of {}", name));
+        }
+    }
+    // 配置没有变化时，htpasswd 文件的内容也可能发生了变化
+    let credentials = "server.auth credentials".to_string();
+    if rules.users_changed(&old_rules) && !changes.contains(&credentials) {
+        changes.push(credentials);
+    }
+    if changes.is_empty() {
+        info!("config not changed");
+        return false;
+    }
+    for change in changes.iter() {
+        info!("config changed: {}", change);
+    }
+    if old.server.upstream != config.server.upstream {
+        warn!("server.upstream only takes effect after restart");
+    }
+    rules.inherit_hits(&old_rules);
+    state.swap(Arc::new(config), rules);
+    true
+}
+
+// 比较两份配置，返回发生变化的内容
+fn changes(old: &Config, new: &Config) -> Vec<String> {
+    let mut result = Vec::new();
+
+    // 不输出密码
+    if old.server.auth.enable != new.server.auth.enable {
+        result.push(format!(
+            "server.auth.enable: {} -> {}",
+            old.server.auth.enable, new.server.auth.enable
+        ));
+    }
+    if old.server.auth.scheme != new.server.auth.scheme {
+        result.push(format!(
+            "server.auth.scheme: {:?} -> {:?}",
+            old.server.auth.scheme, new.server.auth.scheme
+        ));
+    }
+    if old.server.auth.realm != new.server.auth.realm {
+        result.push(format!(
+            "server.auth.realm: {} -> {}",
+            old.server.auth.realm, new.server.auth.realm
+        ));
+    }
+    if old.server.auth.nonce_timeout != new.server.auth.nonce_timeout {
+        result.push(format!(
+            "server.auth.nonce_timeout: {} -> {}",
+            old.server.auth.nonce_timeout, new.server.auth.nonce_timeout
+        ));
+    }
+    if old.server.auth.username != new.server.auth.username
+        || old.server.auth.password != new.server.auth.password
+        || old.server.auth.users != new.server.auth.users
+        || old.server.auth.file != new.server.auth.file
+        || old.server.auth.jwt != new.server.auth.jwt
+    {
+        result.push("server.auth credentials".to_string());
+    }
+    if old.server.auth.lockout != new.server.auth.lockout {
+        result.push("server.auth.lockout".to_string());
+    }
+    if old.server.keep_alive_timeout != new.server.keep_alive_timeout {
+        result.push(format!(
+            "server.keep_alive_timeout: {} -> {}",
+            old.server.keep_alive_timeout, new.server.keep_alive_timeout
+        ));
+    }
+    if old.server.tunnel_idle_timeout != new.server.tunnel_idle_timeout {
+        result.push(format!(
+            "server.tunnel_idle_timeout: {} -> {}",
+            old.server.tunnel_idle_timeout, new.server.tunnel_idle_timeout
+        ));
+    }
+    if old.server.upstream != new.server.upstream {
+        result.push("server.upstream".to_string());
+    }
+    if old.server.acl != new.server.acl {
+        result.push("server.acl".to_string());
+    }
+    if old.server.max_inspect_body_size != new.server.max_inspect_body_size {
+        result.push(format!(
+            "server.max_inspect_body_size: {} -> {}",
+            old.server.max_inspect_body_size, new.server.max_inspect_body_size
+        ));
+    }
+
+    if old.policy.default != new.policy.default {
+        result.push(format!(
+            "policy.default: {:?} -> {:?}",
+            old.policy.default, new.policy.default
+        ));
+    }
+    if old.policy.mode != new.policy.mode {
+        result.push(format!(
+            "policy.mode: {:?} -> {:?}",
+            old.policy.mode, new.policy.mode
+        ));
+    }
+    if old.policy.block != new.policy.block {
+        result.push("policy.block".to_string());
+    }
+    rule_changes(
+        "allow.request",
+        &old.allow.request,
+        &new.allow.request,
+        |r| &r.name,
+        &mut result,
+    );
+    rule_changes(
+        "allow.response",
+        &old.allow.response,
+        &new.allow.response,
+        |r| &r.name,
+        &mut result,
+    );
+    rule_changes(
+        "deny.request",
+        &old.deny.request,
+        &new.deny.request,
+        |r| &r.name,
+        &mut result,
+    );
+    rule_changes(
+        "deny.response",
+        &old.deny.response,
+        &new.deny.response,
+        |r| &r.name,
+        &mut result,
+    );
+    result
+}
+
+// 按照规则的名字比较新增、删除以及修改的规则
+fn rule_changes<T: PartialEq>(
+    section: &str,
+    old: &[T],
+    new: &[T],
+    name: fn(&T) -> &String,
+    result: &mut Vec<String>,
+) {
+    for rule in new.iter() {
+        match old.iter().find(|r| name(r) == name(rule)) {
+            None => result.push(format!("{} `{}` added", section, name(rule))),
+            Some(r) if r != rule => result.push(format!("{} `{}` modified", section, name(rule))),
+            Some(_) => {}
+        }
+    }
+    for rule in old.iter() {
+        if !new.iter().any(|r| name(r) == name(rule)) {
+            result.push(format!("{} `{}` removed", section, name(rule)));
+        }
+    }
+}
+
+#[test]
+fn reload_test() {
+    let path = std::env::temp_dir().join(format!("proxy-reload-{}.yml", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+
+    // 配置文件在临时目录中，公钥使用绝对路径
+    let key = fs::canonicalize("test/jwt-public.pem").unwrap();
+    let content = fs::read_to_string("test/config.yml")
+        .unwrap()
+        .replace("jwt-public.pem", key.to_str().unwrap());
+    fs::write(&path, &content).unwrap();
+    let state = State::new(Arc::new(Config::load(&path).unwrap())).unwrap();
+    let snapshot = state.snapshot().0;
+    assert!(!reload(&state, &path));
+
+    // 配置有误时保留之前的配置
+    fs::write(&path, "server: [").unwrap();
+    assert!(!reload(&state, &path));
+    assert_eq!(state.snapshot().0.server.keep_alive_timeout, 30);
+
+    fs::write(
+        &path,
+        content.replace("keep_alive_timeout: 30", "keep_alive_timeout: 10"),
+    )
+    .unwrap();
+    assert!(reload(&state, &path));
+    assert_eq!(state.snapshot().0.server.keep_alive_timeout, 10);
+    // 之前取得的快照不受影响
+    assert_eq!(snapshot.server.keep_alive_timeout, 30);
+
+    // 只修改了拒绝页面的模板文件
+    let page = format!("{}.html", path);
+    fs::write(&page, "blocked").unwrap();
+    fs::write(
+        &path,
+        content.replace(
+            "policy:\n",
+            &format!("policy:\n    block:\n        file: {}\n", page),
+        ),
+    )
+    .unwrap();
+    assert!(reload(&state, &path));
+    fs::write(&page, "blocked by {{rule}}").unwrap();
+    assert!(reload(&state, &path));
+    assert!(!reload(&state, &path));
+
+    fs::remove_file(&page).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn changes_test() {
+    use crate::config::Response;
+
+    let old = Config::default();
+    let mut new = Config::default();
+    assert!(changes(&old, &new).is_empty());
+
+    new.server.auth.password = "secret".to_string();
+    new.server.auth.scheme = crate::config::Scheme::Digest;
+    new.server.keep_alive_timeout = 10;
+    new.deny.response.push(Response {
+        name: "json".to_string(),
+        ..Response::default()
+    });
+    let result = changes(&old, &new);
+    assert_eq!(
+        result,
+        vec![
+            "server.auth.scheme: Basic -> Digest",
+            "server.auth credentials",
+            "server.keep_alive_timeout: 0 -> 10",
+            "deny.response `json` added",
+        ]
+    );
+    assert!(!result.iter().any(|c| c.contains("secret")));
+}
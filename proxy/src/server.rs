@@ -1,68 +1,118 @@
-use std::net::TcpListener;
+use std::net::TcpListener as StdTcpListener;
 use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
-use log::error;
+use log::{error, info};
+use tokio::net::TcpListener;
+use tokio::runtime::Builder;
+use tokio::time;
 
 use crate::banner;
-use crate::pool::ThreadPool;
+use crate::config::Config;
+use crate::filter::FilterStatus;
+use crate::handler::{handle_stream, State};
+use crate::reload;
+use crate::stats;
 
 use super::iptables::init as init_iptables;
 use super::log::init as init_log;
 
 const VERSION: &str = "v1.0.0";
 
+// 接收连接出错之后等待一段时间再继续，避免错误持续时占满 CPU 以及刷屏日志
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
 /// 代理服务器
 pub struct Server {
     pub port: String,
     pub host: String,
     // 监听socket
-    listener: TcpListener,
-    // pool
-    pool: ThreadPool,
+    listener: StdTcpListener,
+    // 运行时的工作线程数量
+    pool_size: usize,
+    config: Arc<Config>,
+    // 重新加载配置时读取的文件
+    config_path: Option<String>,
+    // 检查配置文件是否修改的间隔
+    watch_interval: Option<Duration>,
 }
 
 impl Server {
     /// 创建一个新的 Server
     ///
-    /// 可以指定对应的地址，端口，线程池大小
-    pub fn new(host: &str, port: &str, pool_size: usize) -> Result<Server, &'static str> {
+    /// 可以指定对应的地址，端口，运行时的工作线程数量以及使用的配置
+    pub fn new(
+        host: &str,
+        port: &str,
+        pool_size: usize,
+        config: Arc<Config>,
+    ) -> Result<Server, &'static str> {
+        if pool_size == 0 {
+            return Err("pool size must be greater than 0");
+        }
+
         // 初始化日志
         init_log();
 
-        let l = match TcpListener::bind(format!("{}:{}", host, port)) {
+        let l = match StdTcpListener::bind(format!("{}:{}", host, port)) {
             Ok(res) => res,
             Err(_) => return Err("bind address failed"),
         };
-        let pool = ThreadPool::new(pool_size);
 
         Ok(Server {
             host: host.to_string(),
             port: port.to_string(),
             listener: l,
-            pool: pool,
+            pool_size,
+            config,
+            config_path: None,
+            watch_interval: None,
         })
     }
 
     // 运行服务器
-    // 1. 初始化iptalbes配置，流量进行重定向
-    // 2. 开启线程池，进行http响应的处理
+    // 1. 创建异步运行时
+    // 2. 每个连接交给一个异步任务处理，连接数量不受线程数量的限制
     // 3. 返回
     pub fn run(&mut self) -> Result<(), String> {
         banner::print(VERSION);
         println!("run server on {}:{}", self.host, self.port);
 
-        for stream in self.listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    if let Err(e) = self.pool.execute(stream) {
-                        error!("pool execute failed: {}", e);
-                        continue;
-                    };
+        let runtime = match Builder::new_multi_thread()
+            .worker_threads(self.pool_size)
+            .enable_all()
+            .build()
+        {
+            Ok(r) => r,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let listener = match self.listener.try_clone() {
+            Ok(l) => l,
+            Err(e) => return Err(e.to_string()),
+        };
+        let state = Arc::new(State::new(self.config.clone())?);
+        let config_path = self.config_path.clone();
+        let watch_interval = self.watch_interval;
+        runtime.block_on(async move {
+            tokio::spawn(stats::on_signal(state.clone()));
+            if let Some(path) = config_path {
+                tokio::spawn(reload::on_signal(state.clone(), path.clone()));
+                if let Some(interval) = watch_interval {
+                    tokio::spawn(reload::watch(state.clone(), path, interval));
                 }
-                Err(e) => return Err(e.to_string()),
             }
-        }
-        Ok(())
+            serve(listener, state).await
+        })
+    }
+
+    /// 运行时从 path 重新加载配置
+    ///
+    /// 收到 SIGHUP 信号时重新加载，指定 watch_interval 时还会定期检查文件是否被修改
+    pub fn reload_from(&mut self, path: &str, watch_interval: Option<Duration>) {
+        self.config_path = Some(path.to_string());
+        self.watch_interval = watch_interval;
     }
 
     // 开启透明代理
@@ -73,3 +123,33 @@ impl Server {
         };
     }
 }
+
+// 接收连接，为每一个连接创建一个异步任务
+async fn serve(listener: StdTcpListener, state: Arc<State>) -> Result<(), String> {
+    if let Err(e) = listener.set_nonblocking(true) {
+        return Err(e.to_string());
+    }
+    let listener = match TcpListener::from_std(listener) {
+        Ok(l) => l,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                // 不在访问控制列表中的客户端直接关闭连接，不读取任何数据
+                let (_, rules) = state.snapshot();
+                if rules.check_client(addr.ip()) == FilterStatus::Reject {
+                    info!("reject client {}", addr);
+                    continue;
+                }
+                tokio::spawn(handle_stream(stream, state.clone()));
+            }
+            // 文件描述符耗尽等错误不影响已有的连接，等待一段时间之后继续接收新的连接
+            Err(e) => {
+                error!("accept connection failed: {}", e);
+                time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! stats.rs 输出规则的命中次数
+//!
+//! 收到 SIGUSR1 信号时在日志中输出每条规则的命中次数，便于确认哪些规则实际生效，
+//! 重新加载配置之后名字相同的规则继续计数
+
+use std::sync::Arc;
+
+use log::{error, info};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::filter::CompiledRuleSet;
+use crate::handler::State;
+
+/// 收到 SIGUSR1 信号时输出当前规则的命中次数
+pub async fn on_signal(state: Arc<State>) {
+    let mut user1 = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("listen SIGUSR1 failed: {}", e);
+            return;
+        }
+    };
+    while user1.recv().await.is_some() {
+        let (_, rules) = state.snapshot();
+        for line in report(&rules) {
+            info!("{}", line);
+        }
+    }
+}
+
+// 每条规则一行，按照匹配的顺序
+fn report(rules: &CompiledRuleSet) -> Vec<String> {
+    rules
+        .hits()
+        .iter()
+        .map(|h| {
+            format!(
+                "rule hits: {} `{}` ({:?}, {:?}): {}",
+                h.kind, h.rule, h.action, h.mode, h.hits
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn report_test() {
+    use crate::config::{Config, Response};
+
+    let mut config = Config::default();
+    config.deny.response.push(Response {
+        name: "pdf".to_string(),
+        ..Response::default()
+    });
+    let rules = CompiledRuleSet::new(&config).unwrap();
+    assert_eq!(
+        report(&rules),
+        vec![
+            "rule hits: request `default` (Allow, Enforce): 0",
+            "rule hits: response `pdf` (Deny, Enforce): 0",
+            "rule hits: response `default` (Allow, Enforce): 0",
+        ]
+    );
+}
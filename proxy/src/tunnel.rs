@@ -0,0 +1,191 @@
+//! tunnel.rs 负责 CONNECT 建立的 tunnel 中数据的双向转发
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+
+// 每个方向转发数据时使用的缓冲区大小
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// tunnel 结束时两个方向转发的数据量
+#[derive(Debug, Default)]
+pub struct Transferred {
+    /// 客户端发送给服务端的字节数
+    pub sent: u64,
+    /// 服务端发送给客户端的字节数
+    pub received: u64,
+    /// 导致 tunnel 结束的错误，正常关闭时为 None
+    pub error: Option<io::Error>,
+}
+
+// 两个方向共享的状态
+struct Activity {
+    start: Instant,
+    // 最近一次转发数据的时间，相对于 start 的毫秒数
+    last: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Activity {
+    fn touch(&self) {
+        self.last
+            .store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // 两个方向都没有数据的时间
+    fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
+
+/// 在客户端和服务端之间双向转发数据
+///
+/// 每个方向独立地等待数据可读，不会占用 CPU 空转；
+/// 一个方向读取到 EOF 时关闭对端的写方向（half-close），另一个方向继续转发，
+/// 两个方向都结束时 tunnel 才结束；任意一个方向出错，
+/// 或者两个方向都超过 idle_timeout 没有数据时立即结束
+pub async fn relay<CR, CW, SR, SW>(
+    client_reader: &mut CR,
+    client_writer: &mut CW,
+    server_reader: &mut SR,
+    server_writer: &mut SW,
+    idle_timeout: Option<Duration>,
+) -> Transferred
+where
+    CR: AsyncRead + Unpin + ?Sized,
+    CW: AsyncWrite + Unpin + ?Sized,
+    SR: AsyncRead + Unpin + ?Sized,
+    SW: AsyncWrite + Unpin + ?Sized,
+{
+    let activity = Activity {
+        start: Instant::now(),
+        last: AtomicU64::new(0),
+        sent: AtomicU64::new(0),
+        received: AtomicU64::new(0),
+    };
+
+    let result = tokio::try_join!(
+        copy_half(
+            client_reader,
+            server_writer,
+            &activity,
+            &activity.sent,
+            idle_timeout
+        ),
+        copy_half(
+            server_reader,
+            client_writer,
+            &activity,
+            &activity.received,
+            idle_timeout
+        ),
+    );
+
+    Transferred {
+        sent: activity.sent.load(Ordering::Relaxed),
+        received: activity.received.load(Ordering::Relaxed),
+        error: result.err(),
+    }
+}
+
+// 单个方向的转发，读取到 EOF 之后关闭 writer 的写方向
+async fn copy_half<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    activity: &Activity,
+    counter: &AtomicU64,
+    idle_timeout: Option<Duration>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0; BUFFER_SIZE];
+    loop {
+        let size = match idle_timeout {
+            Some(idle) => {
+                // 另一个方向有数据时继续等待
+                let idle_for = activity.idle_for();
+                if idle_for >= idle {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "tunnel idle timeout",
+                    ));
+                }
+                match time::timeout(idle - idle_for, reader.read(&mut buf)).await {
+                    Ok(res) => res?,
+                    Err(_) => continue,
+                }
+            }
+            None => reader.read(&mut buf).await?,
+        };
+        if size == 0 {
+            break;
+        }
+        writer.write_all(&buf[..size]).await?;
+        counter.fetch_add(size as u64, Ordering::Relaxed);
+        activity.touch();
+    }
+    // 不会再有数据发送给对端
+    writer.shutdown().await
+}
+
+#[tokio::test]
+async fn relay_half_close_test() {
+    use tokio::io::duplex;
+
+    let (mut client, proxy_client) = duplex(64);
+    let (proxy_server, mut server) = duplex(64);
+
+    let handle = tokio::spawn(async move {
+        let (mut cr, mut cw) = tokio::io::split(proxy_client);
+        let (mut sr, mut sw) = tokio::io::split(proxy_server);
+        relay(&mut cr, &mut cw, &mut sr, &mut sw, None).await
+    });
+
+    // 客户端发送数据之后关闭写方向
+    client.write_all(b"hello").await.unwrap();
+    client.shutdown().await.unwrap();
+
+    // 服务端读取到 EOF，但是仍然可以继续发送响应
+    let mut buf = Vec::new();
+    server.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"hello");
+    server.write_all(b"hello world").await.unwrap();
+    server.shutdown().await.unwrap();
+
+    let mut buf = Vec::new();
+    client.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"hello world");
+
+    let transferred = handle.await.unwrap();
+    assert_eq!(transferred.sent, 5);
+    assert_eq!(transferred.received, 11);
+    assert!(transferred.error.is_none());
+}
+
+#[tokio::test]
+async fn relay_idle_timeout_test() {
+    use tokio::io::duplex;
+
+    let (_client, proxy_client) = duplex(64);
+    let (proxy_server, _server) = duplex(64);
+    let (mut cr, mut cw) = tokio::io::split(proxy_client);
+    let (mut sr, mut sw) = tokio::io::split(proxy_server);
+
+    let transferred = relay(
+        &mut cr,
+        &mut cw,
+        &mut sr,
+        &mut sw,
+        Some(Duration::from_millis(50)),
+    )
+    .await;
+    assert_eq!(transferred.sent, 0);
+    assert_eq!(transferred.error.unwrap().kind(), io::ErrorKind::TimedOut);
+}
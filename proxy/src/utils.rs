@@ -1,4 +1,6 @@
 use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use base64::decode as base64decode;
 
@@ -22,6 +24,19 @@ pub fn decode(string: &String) -> Result<(String, String), &str> {
     Err("Decode authorization information failed")
 }
 
+// 进程内递增的序号
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// 生成请求 ID，由当前时间（秒）和进程内递增的序号组成
+pub fn request_id() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:06x}", secs, sequence)
+}
+
 #[test]
 fn decode_test() {
     let username = "username".to_string();
@@ -35,3 +50,11 @@ fn decode_test() {
     assert_eq!(u, username);
     assert!(u.eq(&username) && p.eq(&passwd));
 }
+
+#[test]
+fn request_id_test() {
+    let first = request_id();
+    let second = request_id();
+    assert_ne!(first, second);
+    assert_eq!(first.split('-').count(), 2);
+}
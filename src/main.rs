@@ -1,5 +1,8 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use clap::{App, Arg};
-use proxy::Server;
+use proxy::{Config, Server};
 
 fn main() {
     let app = App::new("rust proxy")
@@ -23,7 +26,7 @@ fn main() {
             Arg::with_name("pool_size")
                 .short("s")
                 .long("pool_size")
-                .help("proxy server pool size")
+                .help("proxy server worker threads")
                 .default_value("10"),
         )
         .arg(
@@ -33,6 +36,20 @@ fn main() {
                 .help("set transparent proxy [true, false]")
                 .default_value("false"),
         )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .help("config file path")
+                .default_value("config.yml"),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .short("w")
+                .long("watch")
+                .help("reload config file when modified, check interval in seconds, 0 to disable")
+                .default_value("0"),
+        )
         .get_matches();
 
     let host = match app.value_of("host") {
@@ -68,7 +85,17 @@ fn main() {
         None => false,
     };
 
-    let mut s = match Server::new(&host, &port, size) {
+    // 配置文件有误时直接退出，不等到处理请求时才发现
+    let config_path = app.value_of("config").unwrap_or("config.yml");
+    let config = match Config::load(config_path) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let mut s = match Server::new(&host, &port, size, config) {
         Ok(server) => server,
         Err(e) => {
             println!("{}", e);
@@ -80,6 +107,20 @@ fn main() {
         s.init_iptables();
     }
 
+    // 收到 SIGHUP 信号时重新加载配置，同时可以选择监听文件的修改
+    let watch = match app.value_of("watch") {
+        Some(w) => match w.parse::<u64>() {
+            Ok(0) => None,
+            Ok(w) => Some(Duration::from_secs(w)),
+            Err(_) => {
+                println!("watch must be an integer");
+                return;
+            }
+        },
+        None => None,
+    };
+    s.reload_from(config_path, watch);
+
     if let Err(err) = s.run() {
         println!("{}", err)
     };